@@ -0,0 +1,40 @@
+#![allow(clippy::needless_return)]
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use jpeg_decode::{bench, Decoder};
+
+fn idct(c: &mut Criterion) {
+    let basis = bench::Basis::new();
+    let idct_block = bench::idct_block();
+    // A few low frequencies, like a block of a smooth area after
+    // quantization, and a block with every coefficient set
+    let mut sparse: [i16; 64] = [0; 64];
+    for (idx, coefficient) in [(0, -312), (1, 48), (8, -20), (9, 6), (2, -3), (16, 2)] {
+        sparse[idx] = coefficient;
+    }
+    let dense: [i16; 64] = core::array::from_fn(|idx| (idx as i16 * 37) % 101 - 50);
+    c.bench_function("idct_block sparse", |b| b.iter(|| idct_block(black_box(&sparse), &basis)));
+    c.bench_function("idct_block dense", |b| b.iter(|| idct_block(black_box(&dense), &basis)));
+}
+
+fn huffman(c: &mut Criterion) {
+    // Symbols of the Annex K luminance AC table, mostly from the short codes
+    // at the start as in real scans, with an occasional long one
+    let table = bench::huffman_table(&1, &0);
+    let mut seed: u32 = 1;
+    let symbols: Vec<u8> = (0..4096).map(|_| {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        let k = if seed >> 28 == 0 { (seed >> 16) as usize % table.huffman_values.len() } else { (seed >> 16) as usize % 12 };
+        return table.huffman_values[k]
+    }).collect();
+    let data = bench::encode_symbols(&table, &symbols);
+    c.bench_function("next_symbol 4096 AC symbols", |b| b.iter(|| bench::next_symbols(black_box(&data), &table, &symbols.len()).unwrap()));
+}
+
+fn end_to_end(c: &mut Criterion) {
+    let bytes: &[u8] = include_bytes!("../src/images/guy.jpg");
+    c.bench_function("decode guy.jpg", |b| b.iter(|| Decoder::new(black_box(bytes)).decode().unwrap()));
+}
+
+criterion_group!(benches, idct, huffman, end_to_end);
+criterion_main!(benches);
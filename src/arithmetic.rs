@@ -0,0 +1,335 @@
+// Arithmetic decoding of sequential DCT scans (refer to annex D and F.2.4
+// in the spec). Each binary decision is decoded against a statistics bin
+// that adapts its probability estimate as decisions are made. The bins a
+// decision uses depend on the coefficient being decoded and, for DC, on
+// the size of the previous difference.
+//
+// The registers follow libjpeg's arrangement of the decoder, which reads
+// the same bits as the one in annex D but keeps C aligned with A by
+// shifting A rather than C.
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::{ArithmeticTable, DecodeError};
+
+// Probability estimation state machine (refer to table D.2 in the spec):
+// Qe, Next_Index_LPS, Next_Index_MPS and Switch_MPS. The last entry is not
+// in the table. It never adapts and estimates 0.5, which is what the sign
+// of an AC coefficient is decoded with.
+const QE_TABLE: [(u32, u8, u8, bool); 114] = [
+    (0x5a1d,   1,   1, true),  (0x2586,  14,   2, false), (0x1114,  16,   3, false), (0x080b,  18,   4, false),
+    (0x03d8,  20,   5, false), (0x01da,  23,   6, false), (0x00e5,  25,   7, false), (0x006f,  28,   8, false),
+    (0x0036,  30,   9, false), (0x001a,  33,  10, false), (0x000d,  35,  11, false), (0x0006,   9,  12, false),
+    (0x0003,  10,  13, false), (0x0001,  12,  13, false), (0x5a7f,  15,  15, true),  (0x3f25,  36,  16, false),
+    (0x2cf2,  38,  17, false), (0x207c,  39,  18, false), (0x17b9,  40,  19, false), (0x1182,  42,  20, false),
+    (0x0cef,  43,  21, false), (0x09a1,  45,  22, false), (0x072f,  46,  23, false), (0x055c,  48,  24, false),
+    (0x0406,  49,  25, false), (0x0303,  51,  26, false), (0x0240,  52,  27, false), (0x01b1,  54,  28, false),
+    (0x0144,  56,  29, false), (0x00f5,  57,  30, false), (0x00b7,  59,  31, false), (0x008a,  60,  32, false),
+    (0x0068,  62,  33, false), (0x004e,  63,  34, false), (0x003b,  32,  35, false), (0x002c,  33,   9, false),
+    (0x5ae1,  37,  37, true),  (0x484c,  64,  38, false), (0x3a0d,  65,  39, false), (0x2ef1,  67,  40, false),
+    (0x261f,  68,  41, false), (0x1f33,  69,  42, false), (0x19a8,  70,  43, false), (0x1518,  72,  44, false),
+    (0x1177,  73,  45, false), (0x0e74,  74,  46, false), (0x0bfb,  75,  47, false), (0x09f8,  77,  48, false),
+    (0x0861,  78,  49, false), (0x0706,  79,  50, false), (0x05cd,  48,  51, false), (0x04de,  50,  52, false),
+    (0x040f,  50,  53, false), (0x0363,  51,  54, false), (0x02d4,  52,  55, false), (0x025c,  53,  56, false),
+    (0x01f8,  54,  57, false), (0x01a4,  55,  58, false), (0x0160,  56,  59, false), (0x0125,  57,  60, false),
+    (0x00f6,  58,  61, false), (0x00cb,  59,  62, false), (0x00ab,  61,  63, false), (0x008f,  61,  32, false),
+    (0x5b12,  65,  65, true),  (0x4d04,  80,  66, false), (0x412c,  81,  67, false), (0x37d8,  82,  68, false),
+    (0x2fe8,  83,  69, false), (0x293c,  84,  70, false), (0x2379,  86,  71, false), (0x1edf,  87,  72, false),
+    (0x1aa9,  87,  73, false), (0x174e,  72,  74, false), (0x1424,  72,  75, false), (0x119c,  74,  76, false),
+    (0x0f6b,  74,  77, false), (0x0d51,  75,  78, false), (0x0bb6,  77,  79, false), (0x0a40,  77,  48, false),
+    (0x5832,  80,  81, true),  (0x4d1c,  88,  82, false), (0x438e,  89,  83, false), (0x3bdd,  90,  84, false),
+    (0x34ee,  91,  85, false), (0x2eae,  92,  86, false), (0x299a,  93,  87, false), (0x2516,  86,  71, false),
+    (0x5570,  88,  89, true),  (0x4ca9,  95,  90, false), (0x44d9,  96,  91, false), (0x3e22,  97,  92, false),
+    (0x3824,  99,  93, false), (0x32b4,  99,  94, false), (0x2e17,  93,  86, false), (0x56a8,  95,  96, true),
+    (0x4f46, 101,  97, false), (0x47e5, 102,  98, false), (0x41cf, 103,  99, false), (0x3c3d, 104, 100, false),
+    (0x375e,  99,  93, false), (0x5231, 105, 102, false), (0x4c0f, 106, 103, false), (0x4639, 107, 104, false),
+    (0x415e, 103,  99, false), (0x5627, 105, 106, true),  (0x50e7, 108, 107, false), (0x4b85, 109, 103, false),
+    (0x5597, 110, 109, false), (0x504f, 111, 107, false), (0x5a10, 110, 111, true),  (0x5522, 112, 109, false),
+    (0x59eb, 112, 111, true),  (0x5a1d, 113, 113, false)
+];
+
+// Index of the fixed 0.5 estimate in QE_TABLE
+const FIXED_ESTIMATE: u8 = 113;
+
+// A statistics bin holds an index into QE_TABLE in its low 7 bits and the
+// more probable symbol in its high bit. Every bin starts at index 0 with
+// an MPS of 0.
+const MPS: u8 = 0x80;
+
+// Bins per table (refer to tables F.4 and F.5 in the spec). DC uses 49
+// and AC uses 245, rounded up here.
+const DC_BINS: usize = 64;
+const AC_BINS: usize = 256;
+
+// Bins of the DC or AC statistics for a table destination, by index, or
+// the fixed bin for the sign of AC coefficients
+#[derive(Clone, Copy)]
+enum Bin {
+    Dc(usize, usize),
+    Ac(usize, usize),
+    Fixed
+}
+
+// Conditioning set by DAC segments (refer to B.2.4.3 and F.1.4.4 in the
+// spec). Table destinations are 4 bits wide in the scan header, so all 16
+// are kept even though only 0 through 3 are valid.
+struct Conditioning {
+    dc_lower: [u8; 16], // L
+    dc_upper: [u8; 16], // U
+    ac_kx: [u8; 16]     // Kx
+}
+
+impl Conditioning {
+    // Tables that were never conditioned use L = 0, U = 1 and Kx = 5
+    fn new(tables: &[ArithmeticTable]) -> Conditioning {
+        let mut conditioning = Conditioning { dc_lower: [0; 16], dc_upper: [1; 16], ac_kx: [5; 16] };
+        for table in tables.iter() {
+            let destination_id = (table.destination_id & 0x0f) as usize;
+            if table.class == 0 {
+                conditioning.dc_lower[destination_id] = table.value & 0x0f;
+                conditioning.dc_upper[destination_id] = table.value >> 4;
+            }
+            else {
+                conditioning.ac_kx[destination_id] = table.value;
+            }
+        }
+        return conditioning
+    }
+}
+
+pub struct ArithmeticDecoder<'a> {
+    data: &'a [u8],
+    pub byte_idx: usize,
+    // End of the current entropy coded segment. Past it the decoder reads
+    // zeros, as it would once it reached the RSTn or EOI marker.
+    end: usize,
+    c: u32,
+    a: u32,
+    ct: i32,
+    conditioning: Conditioning,
+    dc_stats: [[u8; DC_BINS]; 16],
+    ac_stats: [[u8; AC_BINS]; 16],
+    fixed_bin: u8,
+    // Conditioning category of each component's last DC difference, as
+    // the index of its S0 bin
+    dc_context: Vec<usize>
+}
+
+impl<'a> ArithmeticDecoder<'a> {
+    // end is where the first entropy coded segment ends. tables are the DAC
+    // definitions in effect for the scan.
+    pub fn new(data: &'a [u8], end: &usize, tables: &[ArithmeticTable], total_components: &usize) -> Self {
+        let mut decoder = Self {
+            data,
+            byte_idx: 0,
+            end: 0,
+            c: 0,
+            a: 0,
+            ct: 0,
+            conditioning: Conditioning::new(tables),
+            dc_stats: [[0; DC_BINS]; 16],
+            ac_stats: [[0; AC_BINS]; 16],
+            fixed_bin: FIXED_ESTIMATE,
+            dc_context: vec![0; *total_components]
+        };
+        decoder.restart(&0, end);
+        return decoder
+    }
+
+    // Starts a new entropy coded segment. The statistics, conditioning
+    // categories and registers are all reset (refer to F.2.4.4 in the spec).
+    pub fn restart(&mut self, start: &usize, end: &usize) {
+        self.byte_idx = *start;
+        self.end = (*end).min(self.data.len());
+        // A of 0 and CT of -16 make the first decision read two bytes
+        // into C before anything is decoded (INITDEC)
+        self.c = 0;
+        self.a = 0;
+        self.ct = -16;
+        for stats in self.dc_stats.iter_mut() {
+            stats.fill(0);
+        }
+        for stats in self.ac_stats.iter_mut() {
+            stats.fill(0);
+        }
+        self.dc_context.fill(0);
+    }
+
+    fn next_byte(&mut self) -> u32 {
+        if self.byte_idx >= self.end {
+            return 0
+        }
+        let byte = self.data[self.byte_idx];
+        self.byte_idx += 1;
+        return byte as u32
+    }
+
+    fn stats(&mut self, bin: Bin) -> &mut u8 {
+        return match bin {
+            Bin::Dc(table, idx) => &mut self.dc_stats[table][idx],
+            Bin::Ac(table, idx) => &mut self.ac_stats[table][idx],
+            Bin::Fixed => &mut self.fixed_bin
+        }
+    }
+
+    // DECODE and RENORMD (refer to D.2.3 and D.2.6 in the spec)
+    fn decode(&mut self, bin: Bin) -> u8 {
+        while self.a < 0x8000 {
+            self.ct -= 1;
+            if self.ct < 0 {
+                self.c = (self.c << 8) | self.next_byte();
+                self.ct += 8;
+                if self.ct < 0 {
+                    // Still reading the two initial bytes
+                    self.ct += 1;
+                    if self.ct == 0 {
+                        self.a = 0x8000;
+                    }
+                }
+            }
+            self.a <<= 1;
+        }
+        let state = *self.stats(bin);
+        let (qe, next_lps, next_mps, switch_mps) = QE_TABLE[(state & !MPS) as usize];
+        let after_mps = (state & MPS) | next_mps;
+        let after_lps = ((state & MPS) ^ if switch_mps { MPS } else { 0 }) | next_lps;
+        let mut symbol = state >> 7;
+        self.a -= qe;
+        let threshold = self.a << self.ct;
+        let next_state = if self.c >= threshold {
+            // The decision landed in the LPS subinterval, unless the
+            // subintervals were exchanged because the LPS one was larger
+            self.c -= threshold;
+            let exchanged = self.a < qe;
+            self.a = qe;
+            if exchanged {
+                after_mps
+            }
+            else {
+                symbol ^= 1;
+                after_lps
+            }
+        }
+        else if self.a < 0x8000 {
+            if self.a < qe {
+                symbol ^= 1;
+                after_lps
+            }
+            else {
+                after_mps
+            }
+        }
+        else {
+            state
+        };
+        *self.stats(bin) = next_state;
+        return symbol
+    }
+
+    // Decodes one block of coefficients in zigzag order, adding the
+    // predictor to the DC difference like the Huffman decoder does
+    // (refer to F.2.4.1 and F.2.4.2 in the spec)
+    pub fn decode_block(
+        &mut self,
+        prev_dc: &mut i16,
+        component: &usize,
+        dc_table: &u8,
+        ac_table: &u8
+    ) -> Result<[i16; 64], DecodeError> {
+        let mut block: [i16; 64] = [0; 64];
+        let dc_table = (*dc_table & 0x0f) as usize;
+        let ac_table = (*ac_table & 0x0f) as usize;
+
+        // DC difference (refer to figure F.19 in the spec). S0 is picked by
+        // the previous difference and is followed by SS, SP and SN.
+        let s0 = self.dc_context[*component];
+        if self.decode(Bin::Dc(dc_table, s0)) == 0 {
+            self.dc_context[*component] = 0;
+        }
+        else {
+            let sign = self.decode(Bin::Dc(dc_table, s0 + 1)) as usize;
+            let mut idx = s0 + 2 + sign;
+            let mut magnitude: u32 = self.decode(Bin::Dc(dc_table, idx)) as u32;
+            if magnitude != 0 {
+                // X1 through X15 count the magnitude category
+                idx = 20;
+                while self.decode(Bin::Dc(dc_table, idx)) != 0 {
+                    magnitude <<= 1;
+                    if magnitude == 0x8000 {
+                        return Err(DecodeError::InvalidCoefficient);
+                    }
+                    idx += 1;
+                }
+            }
+            // Conditioning category for the next difference (refer to
+            // F.1.4.4.1.2 in the spec)
+            let lower = (1u32 << self.conditioning.dc_lower[dc_table]) >> 1;
+            let upper = (1u32 << self.conditioning.dc_upper[dc_table]) >> 1;
+            self.dc_context[*component] = if magnitude < lower {
+                0
+            }
+            else if magnitude > upper {
+                12 + sign * 4
+            }
+            else {
+                4 + sign * 4
+            };
+            let difference = self.decode_magnitude_bits(magnitude, sign, Bin::Dc(dc_table, idx + 14));
+            *prev_dc = (difference as i16).wrapping_add(*prev_dc);
+        }
+        block[0] = *prev_dc;
+
+        // AC coefficients (refer to figure F.20 in the spec). Each index k
+        // has its own SE, S0 and SS bins, 3 apart.
+        let kx = self.conditioning.ac_kx[ac_table] as usize;
+        let mut k: usize = 1;
+        while k < 64 {
+            let mut idx = 3 * (k - 1);
+            if self.decode(Bin::Ac(ac_table, idx)) != 0 {
+                // End of block
+                break;
+            }
+            while self.decode(Bin::Ac(ac_table, idx + 1)) == 0 {
+                idx += 3;
+                k += 1;
+                if k > 63 {
+                    return Err(DecodeError::InvalidCoefficient);
+                }
+            }
+            let sign = self.decode(Bin::Fixed) as usize;
+            idx += 2;
+            let mut magnitude: u32 = self.decode(Bin::Ac(ac_table, idx)) as u32;
+            if magnitude != 0 && self.decode(Bin::Ac(ac_table, idx)) != 0 {
+                magnitude <<= 1;
+                // Low and high frequencies count their categories in
+                // separate bins, split at Kx
+                idx = if k <= kx { 189 } else { 217 };
+                while self.decode(Bin::Ac(ac_table, idx)) != 0 {
+                    magnitude <<= 1;
+                    if magnitude == 0x8000 {
+                        return Err(DecodeError::InvalidCoefficient);
+                    }
+                    idx += 1;
+                }
+            }
+            block[k] = self.decode_magnitude_bits(magnitude, sign, Bin::Ac(ac_table, idx + 14)) as i16;
+            k += 1;
+        }
+        return Ok(block)
+    }
+
+    // Reads the bits below the leading 1 of magnitude (refer to figure F.24
+    // in the spec). The value is one more than the magnitude decoded.
+    fn decode_magnitude_bits(&mut self, magnitude: u32, sign: usize, bin: Bin) -> i32 {
+        let mut value = magnitude;
+        let mut bit = magnitude >> 1;
+        while bit != 0 {
+            if self.decode(bin) != 0 {
+                value |= bit;
+            }
+            bit >>= 1;
+        }
+        let value = value as i32 + 1;
+        return if sign == 1 { -value } else { value }
+    }
+}
@@ -0,0 +1,54 @@
+// Entry points for the criterion benchmarks in benches/, which can only
+// reach public items. These wrap internals of the decoder and aren't part
+// of its API.
+use alloc::vec::Vec;
+
+use crate::{annex_k, idct, next_symbol, BitReader, DecodeError, HuffmanTable};
+
+pub use crate::idct::{Basis, BlockIdct};
+
+// The idct the decoder picks for this CPU. It takes coefficients and
+// returns samples, both in natural order.
+pub fn idct_block() -> BlockIdct {
+    return idct::select()
+}
+
+// The Annex K table for the given class (0 for DC, 1 for AC) and
+// destination, which most encoders use as is
+pub fn huffman_table(class: &u8, destination_id: &u8) -> HuffmanTable {
+    return annex_k::huffman_table(class, destination_id).expect("(huffman_table) Annex K has tables for destinations 0 and 1")
+}
+
+// Packs the codes of symbols back to back, padding the last byte with
+// 1-bits as an encoder would. Every symbol must be in the table.
+pub fn encode_symbols(table: &HuffmanTable, symbols: &[u8]) -> Vec<u8> {
+    let sizes = table.generate_size_table();
+    let codes = table.generate_code_table(&sizes).expect("(encode_symbols) Table must be valid");
+    let mut bytes: Vec<u8> = Vec::new();
+    let mut bits: u32 = 0;
+    let mut total_bits: u8 = 0;
+    for symbol in symbols.iter() {
+        let k = table.huffman_values.iter().position(|value| value == symbol).expect("(encode_symbols) Symbol not in table");
+        bits = (bits << sizes[k]) | u32::from(codes[k]);
+        total_bits += sizes[k];
+        while total_bits >= 8 {
+            total_bits -= 8;
+            bytes.push((bits >> total_bits) as u8);
+        }
+    }
+    if total_bits > 0 {
+        bytes.push(((bits << (8 - total_bits)) as u8) | (0xff >> total_bits));
+    }
+    return bytes
+}
+
+// Reads count symbols from data with next_symbol, as decode_block does,
+// returning their sum so that the reads can't be optimized away
+pub fn next_symbols(data: &[u8], table: &HuffmanTable, count: &usize) -> Result<u32, DecodeError> {
+    let mut bit_reader = BitReader::new(data, &true);
+    let mut sum: u32 = 0;
+    for _ in 0..*count {
+        sum += u32::from(next_symbol(&mut bit_reader, table)?);
+    }
+    return Ok(sum)
+}
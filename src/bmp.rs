@@ -0,0 +1,154 @@
+// BMP output for the command line tool, which is only built with the std
+// feature.
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use jpeg_decode::{convert_pixels, Image, PixelFormat};
+
+// Rows keep the image's top-down order since create_bmp writes a top-down
+// BMP. Color pixels are stored as B, G, R.
+pub fn bmp_data_from_image(image: &Image) -> Vec<u8> {
+    let bgr: Image;
+    let image = match image.format {
+        PixelFormat::Gray8 | PixelFormat::Bgr8 => image,
+        PixelFormat::Rgb8 | PixelFormat::Rgba8 | PixelFormat::Bgra8 => {
+            bgr = convert_pixels(image, &PixelFormat::Bgr8);
+            &bgr
+        }
+    };
+    let width = image.width as usize;
+    let height = image.height as usize;
+    let row_length = width * image.total_components as usize;
+    let padding = bmp_row_padding(&width, &image.total_components);
+    let mut image_data: Vec<u8> = Vec::with_capacity((row_length + padding) * height);
+    for row in image.pixels.chunks(row_length) {
+        image_data.extend(row);
+        // Account for padding here
+        image_data.resize(image_data.len() + padding, 0);
+    }
+    return image_data;
+}
+
+// Each BMP row is padded so that its length in bytes is a multiple of 4
+fn bmp_row_padding(width: &usize, total_components: &u8) -> usize {
+    let row_length = width * *total_components as usize;
+    return (4 - row_length % 4) % 4
+}
+
+// Writes the BMP to a new file at path, replacing any file already there
+pub fn create_bmp(path: &Path, image_data: &Vec<u8>, width: &usize, height: &usize, total_components: &u8) -> std::io::Result<()> {
+    let mut bmp = File::create(path)?;
+    return write_bmp(&mut bmp, image_data, width, height, total_components)
+}
+
+// Writes a complete BMP file, headers included, to any writer
+pub fn write_bmp<W: Write>(
+    writer: &mut W,
+    image_data: &Vec<u8>,
+    width: &usize,
+    height: &usize,
+    total_components: &u8
+) -> std::io::Result<()> {
+    let padding = bmp_row_padding(width, total_components);
+    // For 24 bits per pixel, or 3 color components, we use 3 bytes per pixel
+    let image_size: u32 = *width as u32 * *height as u32 * *total_components as u32 + (padding * height) as u32;
+    // 8 bit grayscale pixels are indices into a color table, which maps
+    // each index to the gray of the same level. Each entry is B, G, R and an
+    // unused byte.
+    let color_table: Vec<u8> = match total_components {
+        1 => (0..=255u8).flat_map(|level| [level, level, level, 0]).collect(),
+        _ => Vec::new()
+    };
+    let data_offset: u32 = 54 + color_table.len() as u32;
+    let file_size: u32 = data_offset + image_size;
+    // Construct bmp header
+    // BM (2), file size (4), unused (4), data offset (4)
+    let mut header: [u8; 14] = [0x42, 0x4d, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+    for (idx, byte) in file_size.to_le_bytes().iter().enumerate() {
+        header[idx + 2] = *byte;
+    }
+    for (idx, byte) in data_offset.to_le_bytes().iter().enumerate() {
+        header[idx + 10] = *byte;
+    }
+    // Construct bmp info header
+    let mut info_header: [u8; 40] = [0; 40];
+    info_header[0] = 0x28; // size of info header
+    for (idx, byte) in width.to_le_bytes().iter().enumerate() {
+        info_header[idx + 4] = *byte; // width of image
+    }
+    // A negative height marks the rows as stored top-down
+    let top_down_height: i32 = -(*height as i32);
+    for (idx, byte) in top_down_height.to_le_bytes().iter().enumerate() {
+        info_header[idx + 8] = *byte; // height of image
+    }
+    info_header[12] = 0x01; // number of planes
+    match total_components {
+        1 => info_header[14] = 0x08, // 8 bits per pixel
+        3 => info_header[14] = 0x18, // 24 bits per pixel
+        _ => return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Unsupported amount of components. 1 component (greyscale) or 3 components (24 bit) are supported."
+        ))
+    }
+    // offset 16 = type of compression (none)
+    // offset 20 = compressed image size, but it can be left at 0 since we didnt compress
+    for (idx, byte) in image_size.to_le_bytes().iter().enumerate() {
+        info_header[idx + 20] = *byte; // compressed image size
+    }
+    // offset 24 & 28 = x and y pixels per meter. Skippable.
+    // offset 32 = colors used. 0 means the full 2^n colors for n bits per pixel.
+    // offset 36 = Important colors. 0 means all colors are important
+    let mut bmp_data: Vec<u8> = Vec::new();
+    bmp_data.extend_from_slice(&header);
+    bmp_data.extend_from_slice(&info_header);
+    bmp_data.extend(color_table);
+    bmp_data.extend(image_data);
+    return writer.write_all(&bmp_data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bmp_can_be_written_to_memory() {
+        let image = Image { width: 2, height: 2, total_components: 3, format: PixelFormat::Rgb8, pixels: vec![9; 12], truncated: false };
+        let image_data = bmp_data_from_image(&image);
+        let mut bmp: Vec<u8> = Vec::new();
+        write_bmp(&mut bmp, &image_data, &2, &2, &3).unwrap();
+        assert_eq!(&bmp[..2], b"BM");
+        assert_eq!(bmp.len(), 54 + 2 * 8);
+        assert_eq!(u32::from_le_bytes(bmp[2..6].try_into().unwrap()), bmp.len() as u32);
+        assert_eq!(i32::from_le_bytes(bmp[22..26].try_into().unwrap()), -2);
+        assert_eq!(&bmp[54..], &image_data[..]);
+
+        // Grayscale is written as 8 bits per pixel with a gray color table
+        let gray = Image { width: 3, height: 2, total_components: 1, format: PixelFormat::Gray8, pixels: vec![0, 100, 255, 1, 2, 3], truncated: false };
+        let image_data = bmp_data_from_image(&gray);
+        assert_eq!(image_data, vec![0, 100, 255, 0, 1, 2, 3, 0]);
+        let mut bmp: Vec<u8> = Vec::new();
+        write_bmp(&mut bmp, &image_data, &3, &2, &1).unwrap();
+        let data_offset = u32::from_le_bytes(bmp[10..14].try_into().unwrap()) as usize;
+        assert_eq!(data_offset, 54 + 256 * 4);
+        assert_eq!(u16::from_le_bytes([bmp[28], bmp[29]]), 8);
+        assert_eq!(&bmp[54 + 100 * 4..54 + 101 * 4], &[100, 100, 100, 0]);
+        assert_eq!(&bmp[data_offset..], &image_data[..]);
+        assert_eq!(u32::from_le_bytes(bmp[2..6].try_into().unwrap()), bmp.len() as u32);
+
+        // Other component counts can't be written, and nor can a file in a
+        // directory that doesn't exist
+        assert!(write_bmp(&mut Vec::new(), &vec![0; 8], &1, &1, &4).is_err());
+        assert!(create_bmp(Path::new("no such directory/out.bmp"), &image_data, &3, &2, &1).is_err());
+    }
+
+    #[test]
+    fn bmp_rows_are_padded_to_four_bytes() {
+        // (width, bytes per 24-bit row including padding)
+        for (width, stride) in [(1, 4), (2, 8), (3, 12), (5, 16), (7, 24)] {
+            let image = Image { width, height: 2, total_components: 3, format: PixelFormat::Rgb8, pixels: vec![0; width as usize * 2 * 3], truncated: false };
+            assert_eq!(bmp_data_from_image(&image).len(), stride * 2);
+            assert_eq!(bmp_row_padding(&(width as usize), &3), stride - width as usize * 3);
+        }
+    }
+}
@@ -0,0 +1,166 @@
+// Inverse DCT of an 8x8 block (refer to A.3.3 in the spec). The 2D sum is
+// separable, so it is computed as a pass over each row of coefficients
+// followed by a pass down each column.
+//
+// With std, the CPU is checked for AVX2 (x86_64) or NEON (aarch64), which
+// run both passes on 4 or 2 f64 lanes at once. The vector paths perform the
+// same operations in the same order as the scalar one, so every path gives
+// identical samples.
+use crate::math;
+
+// basis[u][x] = C(u) / 2 * cos((2x + 1) * u * pi / 16), where C(0) is
+// 1 / sqrt(2) and C(u) is 1 otherwise. Each row holds one frequency for all
+// 8 outputs so that it can be loaded as vectors.
+pub struct Basis([[f64; 8]; 8]);
+
+impl Basis {
+    pub fn new() -> Basis {
+        let mut basis: [[f64; 8]; 8] = [[0.0; 8]; 8];
+        for (u, row) in basis.iter_mut().enumerate() {
+            let scale: f64 = if u == 0 { 1.0 / math::sqrt(2.0) } else { 1.0 } / 2.0;
+            for (x, value) in row.iter_mut().enumerate() {
+                *value = scale * math::cos((2.0 * x as f64 + 1.0) * u as f64 * core::f64::consts::PI / 16.0);
+            }
+        }
+        return Basis(basis)
+    }
+}
+
+impl Default for Basis {
+    fn default() -> Basis {
+        return Basis::new()
+    }
+}
+
+pub type BlockIdct = fn(&[i16; 64], &Basis) -> [i16; 64];
+
+// Picks the fastest implementation the CPU supports. Call it once and keep
+// the result rather than checking for every block.
+pub fn select() -> BlockIdct {
+    #[cfg(all(feature = "std", target_arch = "x86_64"))]
+    if std::is_x86_feature_detected!("avx2") {
+        return avx2
+    }
+    #[cfg(all(feature = "std", target_arch = "aarch64"))]
+    if std::arch::is_aarch64_feature_detected!("neon") {
+        return neon
+    }
+    return scalar
+}
+
+// Takes coefficients and returns samples, both in natural (row-major) order
+pub fn scalar(block: &[i16; 64], basis: &Basis) -> [i16; 64] {
+    // rows[v * 8 + x] is row v of the coefficients transformed along u.
+    // Zero coefficients, usually most of them, add nothing and are skipped.
+    let mut rows: [f64; 64] = [0.0; 64];
+    for v in 0..8 {
+        for u in 0..8 {
+            let coefficient = block[v * 8 + u];
+            if coefficient == 0 {
+                continue;
+            }
+            for x in 0..8 {
+                rows[v * 8 + x] += coefficient as f64 * basis.0[u][x];
+            }
+        }
+    }
+    let mut samples: [i16; 64] = [0; 64];
+    for y in 0..8 {
+        for x in 0..8 {
+            let mut sum: f64 = 0.0;
+            for v in 0..8 {
+                sum += basis.0[v][y] * rows[v * 8 + x];
+            }
+            samples[y * 8 + x] = math::round(sum) as i16;
+        }
+    }
+    return samples
+}
+
+#[cfg(all(feature = "std", target_arch = "x86_64"))]
+fn avx2(block: &[i16; 64], basis: &Basis) -> [i16; 64] {
+    // Safety: select only returns this function once AVX2 was detected
+    return unsafe { avx2_idct(block, basis) }
+}
+
+#[cfg(all(feature = "std", target_arch = "x86_64"))]
+#[target_feature(enable = "avx2")]
+fn avx2_idct(block: &[i16; 64], basis: &Basis) -> [i16; 64] {
+    use core::arch::x86_64::*;
+    // Each row of 8 outputs is held in two vectors of 4
+    let mut rows: [[__m256d; 2]; 8] = [[_mm256_setzero_pd(); 2]; 8];
+    for v in 0..8 {
+        for u in 0..8 {
+            let coefficient = block[v * 8 + u];
+            if coefficient == 0 {
+                continue;
+            }
+            let coefficient = _mm256_set1_pd(coefficient as f64);
+            for half in 0..2 {
+                // Safety: the load reads 4 of the row's 8 values
+                let basis_values = unsafe { _mm256_loadu_pd(basis.0[u][half * 4..].as_ptr()) };
+                rows[v][half] = _mm256_add_pd(rows[v][half], _mm256_mul_pd(coefficient, basis_values));
+            }
+        }
+    }
+    let mut samples: [i16; 64] = [0; 64];
+    let mut sums: [f64; 4] = [0.0; 4];
+    for y in 0..8 {
+        for half in 0..2 {
+            let mut sum = _mm256_setzero_pd();
+            for v in 0..8 {
+                sum = _mm256_add_pd(sum, _mm256_mul_pd(_mm256_set1_pd(basis.0[v][y]), rows[v][half]));
+            }
+            // Safety: sums holds exactly 4 values
+            unsafe { _mm256_storeu_pd(sums.as_mut_ptr(), sum) };
+            for (lane, value) in sums.iter().enumerate() {
+                samples[y * 8 + half * 4 + lane] = math::round(*value) as i16;
+            }
+        }
+    }
+    return samples
+}
+
+#[cfg(all(feature = "std", target_arch = "aarch64"))]
+fn neon(block: &[i16; 64], basis: &Basis) -> [i16; 64] {
+    // Safety: select only returns this function once NEON was detected
+    return unsafe { neon_idct(block, basis) }
+}
+
+#[cfg(all(feature = "std", target_arch = "aarch64"))]
+#[target_feature(enable = "neon")]
+fn neon_idct(block: &[i16; 64], basis: &Basis) -> [i16; 64] {
+    use core::arch::aarch64::*;
+    // Each row of 8 outputs is held in four vectors of 2
+    let mut rows: [[float64x2_t; 4]; 8] = [[vdupq_n_f64(0.0); 4]; 8];
+    for v in 0..8 {
+        for u in 0..8 {
+            let coefficient = block[v * 8 + u];
+            if coefficient == 0 {
+                continue;
+            }
+            let coefficient = vdupq_n_f64(coefficient as f64);
+            for quarter in 0..4 {
+                // Safety: the load reads 2 of the row's 8 values
+                let basis_values = unsafe { vld1q_f64(basis.0[u][quarter * 2..].as_ptr()) };
+                rows[v][quarter] = vaddq_f64(rows[v][quarter], vmulq_f64(coefficient, basis_values));
+            }
+        }
+    }
+    let mut samples: [i16; 64] = [0; 64];
+    let mut sums: [f64; 2] = [0.0; 2];
+    for y in 0..8 {
+        for quarter in 0..4 {
+            let mut sum = vdupq_n_f64(0.0);
+            for v in 0..8 {
+                sum = vaddq_f64(sum, vmulq_f64(vdupq_n_f64(basis.0[v][y]), rows[v][quarter]));
+            }
+            // Safety: sums holds exactly 2 values
+            unsafe { vst1q_f64(sums.as_mut_ptr(), sum) };
+            for (lane, value) in sums.iter().enumerate() {
+                samples[y * 8 + quarter * 2 + lane] = math::round(*value) as i16;
+            }
+        }
+    }
+    return samples
+}
@@ -0,0 +1,30 @@
+// Conversions from decoded images into `image` crate buffers.
+// Enabled with the image-integration feature.
+use image::{DynamicImage, GrayImage, RgbImage, RgbaImage};
+
+use crate::{convert_pixels, Image, PixelFormat};
+
+// Image pixels are already top-down and row-major, which is the layout the
+// `image` crate expects, so the buffer can be handed over as-is. The crate
+// has no BGR buffer, so Bgr8 and Bgra8 pixels are repacked as RGB or RGBA
+// first.
+pub fn to_image_buffer(img: &Image) -> DynamicImage {
+    let width = u32::from(img.width);
+    let height = u32::from(img.height);
+    match img.format {
+        PixelFormat::Gray8 => DynamicImage::ImageLuma8(
+            GrayImage::from_raw(width, height, img.pixels.clone())
+                .expect("(to_image_buffer) Pixel data does not match image dimensions")
+        ),
+        PixelFormat::Rgb8 => DynamicImage::ImageRgb8(
+            RgbImage::from_raw(width, height, img.pixels.clone())
+                .expect("(to_image_buffer) Pixel data does not match image dimensions")
+        ),
+        PixelFormat::Rgba8 => DynamicImage::ImageRgba8(
+            RgbaImage::from_raw(width, height, img.pixels.clone())
+                .expect("(to_image_buffer) Pixel data does not match image dimensions")
+        ),
+        PixelFormat::Bgr8 => to_image_buffer(&convert_pixels(img, &PixelFormat::Rgb8)),
+        PixelFormat::Bgra8 => to_image_buffer(&convert_pixels(img, &PixelFormat::Rgba8))
+    }
+}
@@ -0,0 +1,25 @@
+// JSON dump of the parsed segments, printed by the --info flag.
+// Enabled with the json feature.
+use serde::Serializer;
+
+use crate::{application_identifier, text_until_nul, Frame};
+
+pub fn frame_to_string(frame: &Frame) -> String {
+    return serde_json::to_string_pretty(frame).expect("(frame_to_string) Frame serialization cannot fail")
+}
+
+// serde only implements Serialize for arrays of up to 32 elements
+pub fn elements<S: Serializer>(elements: &[u16; 64], serializer: S) -> Result<S::Ok, S::Error> {
+    return serializer.collect_seq(elements.iter())
+}
+
+pub fn text<S: Serializer>(bytes: &Vec<u8>, serializer: S) -> Result<S::Ok, S::Error> {
+    return serializer.serialize_str(&text_until_nul(bytes))
+}
+
+pub fn identifier<S: Serializer>(data: &Vec<u8>, serializer: S) -> Result<S::Ok, S::Error> {
+    return match application_identifier(data) {
+        Some(identifier) => serializer.serialize_some(identifier),
+        None => serializer.serialize_none()
+    }
+}
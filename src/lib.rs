@@ -0,0 +1,5472 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+#![allow(clippy::needless_return, clippy::ptr_arg, clippy::too_many_arguments, clippy::needless_range_loop, clippy::type_complexity)]
+
+// The decode path only needs alloc. File output and warnings on stderr are
+// limited to the std feature, and without it float math comes from libm.
+extern crate alloc;
+
+#[cfg(not(any(feature = "std", feature = "libm")))]
+compile_error!("Either the std feature or, for no_std builds, the libm feature is needed for float math.");
+
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+// Reports a problem that decoding recovered from
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "std")]
+        eprintln!($($arg)*);
+        #[cfg(not(feature = "std"))]
+        let _ = core::format_args!($($arg)*);
+    };
+}
+
+mod annex_k;
+mod arithmetic;
+#[doc(hidden)]
+pub mod bench;
+mod idct;
+#[cfg(feature = "json")]
+pub mod json;
+mod math;
+mod zigzag;
+#[cfg(feature = "image-integration")]
+pub mod image_integration;
+
+#[non_exhaustive]
+pub struct Markers;
+
+impl Markers {
+    // ALL MARKERS ARE 2 BYTES (u16)
+    // THE FIRST BYTE IS ALWAYS 0xFF (big endian)
+    // Start of Frame (SOF), non-differential, Huffman coding
+    pub const SOF0:  u8 = 0xc0; // Baseline DCT
+    pub const SOF1:  u8 = 0xc1; // Extended Sequential DCT
+    pub const SOF2:  u8 = 0xc2; // Progressive DCT
+    pub const SOF3:  u8 = 0xc3; // Lossless (sequential)
+    // Start of Frame (SOF), differential, Huffman coding
+    pub const SOF5:  u8 = 0xc5; // Differential sequential DCT
+    pub const SOF6:  u8 = 0xc6; // Differential progressive DCT
+    pub const SOF7:  u8 = 0xc7; // Differential lossless (sequential)
+    // Start of Frame (SOF), non-differential, arithmetic coding
+    pub const JPG:   u8 = 0xc8; // Reserved for JPEG extensions
+    pub const SOF9:  u8 = 0xc9; // Extended sequential DCT
+    pub const SOF10: u8 = 0xca; // Progressive DCT
+    pub const SOF11: u8 = 0xcb; // Lossless (sequential)
+    // Start of Frame (SOF), differential, arithmetic coding
+    pub const SOF13: u8 = 0xcd; // Differential sequential DCT
+    pub const SOF14: u8 = 0xce; // Differential progressive DCT
+    pub const SOF15: u8 = 0xcf; // Differential lossless (sequential)
+    // Huffman table spec
+    pub const DHT:   u8 = 0xc4; // Define Huffman Table(s)
+    // Arithmetic coding conditioning spec
+    pub const DAC:   u8 = 0xcc; // Define Arithmetic Coding conditioning(s)
+    // Restart interval termination
+    pub const RST0:  u8 = 0xd0;
+    pub const RST1:  u8 = 0xd1;
+    pub const RST2:  u8 = 0xd2;
+    pub const RST3:  u8 = 0xd3;
+    pub const RST4:  u8 = 0xd4;
+    pub const RST5:  u8 = 0xd5;
+    pub const RST6:  u8 = 0xd6;
+    pub const RST7:  u8 = 0xd7;
+    pub const SOI:   u8 = 0xd8; // Start of Image
+    pub const EOI:   u8 = 0xd9; // End of Image
+    pub const SOS:   u8 = 0xda; // Start of Scan
+    pub const DQT:   u8 = 0xdb; // Define Quantization Table(s)
+    pub const DNL:   u8 = 0xdc; // Define number of lines
+    pub const DRI:   u8 = 0xdd; // Define restart interval
+    pub const DHP:   u8 = 0xde; // Define hierarchical progression
+    pub const EXP:   u8 = 0xdf; // Expand reference component(s)
+    // Reserved for application segments
+    pub const APP0:  u8 = 0xe0;
+    pub const APP1:  u8 = 0xe1;
+    pub const APP2:  u8 = 0xe2;
+    pub const APP3:  u8 = 0xe3;
+    pub const APP4:  u8 = 0xe4;
+    pub const APP5:  u8 = 0xe5;
+    pub const APP6:  u8 = 0xe6;
+    pub const APP7:  u8 = 0xe7;
+    pub const APP8:  u8 = 0xe8;
+    pub const APP9:  u8 = 0xe9;
+    pub const APP10: u8 = 0xea;
+    pub const APP11: u8 = 0xeb;
+    pub const APP12: u8 = 0xec;
+    pub const APP13: u8 = 0xed;
+    pub const APP14: u8 = 0xee;
+    pub const APP15: u8 = 0xef;
+    // Reserved for JPEG extensions
+    pub const JPG0:  u8 = 0xf0;
+    pub const JPG1:  u8 = 0xf1;
+    pub const JPG2:  u8 = 0xf2;
+    pub const JPG3:  u8 = 0xf3;
+    pub const JPG4:  u8 = 0xf4;
+    pub const JPG5:  u8 = 0xf5;
+    pub const JPG6:  u8 = 0xf6;
+    pub const JPG7:  u8 = 0xf7;
+    pub const JPG8:  u8 = 0xf8;
+    pub const JPG9:  u8 = 0xf9;
+    pub const JPG10: u8 = 0xfa;
+    pub const JPG11: u8 = 0xfb;
+    pub const JPG12: u8 = 0xfc;
+    pub const JPG13: u8 = 0xfd;
+    // Other reserved markers
+    pub const COM:   u8 = 0xfe; // Comment
+    pub const MRK:   u8 = 0xff; // Marker start
+    pub const TEM:   u8 = 0x01; // For temporary private use in arithmetic coding
+    pub const ESC:   u8 = 0x00; // Escapes preceeding 0xFF byte (NOT IN SPEC)
+    // 0xff02 through 0xffbf are reserved
+}
+
+#[derive(Default, Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub struct Frame {
+    pub frame_header: FrameHeader,
+    pub scans: Vec<Scan>,
+    pub lines: Option<NumberOfLines>,
+    // Tables are indexed by their destination id. A table defined later in
+    // the file replaces any earlier table with the same destination.
+    pub quantization_tables: [Option<QuantizationTable>; 4],
+    pub dc_huffman_tables: [Option<HuffmanTable>; 4],
+    pub ac_huffman_tables: [Option<HuffmanTable>; 4],
+    pub arithmetic_tables: Vec<ArithmeticTable>,
+    pub restart_interval: Option<RestartInterval>,
+    pub comments: Vec<Comment>,
+    pub application_data: Vec<ApplicationData>,
+    pub expand_reference: Option<ExpandReference>,
+    pub color_transform: Option<ColorTransform>, // From an Adobe APP14 segment, if present
+    pub trailer: Vec<u8>, // Bytes following the EOI marker
+}
+
+impl Frame {
+    pub fn comments_text(&self) -> Vec<Cow<'_, str>> {
+        return self.comments.iter().map(|comment| comment.as_str()).collect()
+    }
+
+    // Byte ranges of the images listed by a Multi-Picture Format APP2
+    // segment, primary image first, within the file the frame was read
+    // from. Phones store depth maps, HDR gain maps and the second view of
+    // stereo pairs this way, each as a complete JPEG that can be decoded on
+    // its own. Empty when there is no MPF segment or its index can't be
+    // read. The ranges aren't checked against the file's length.
+    pub fn mpf_images(&self) -> Vec<Range<usize>> {
+        return self.application_data
+            .iter()
+            .find_map(|app_data| app_data.mpf_images())
+            .unwrap_or_default()
+    }
+
+    // The coding process selected by the SOF marker, or None before one
+    // has been read
+    pub fn coding_process(&self) -> Option<CodingProcess> {
+        return self.frame_header.coding_process()
+    }
+
+    // Each component's parameters and plane size, in frame header order
+    pub fn component_info(&self) -> Vec<ComponentInfo> {
+        return self.frame_header.component_info()
+    }
+
+    // Progressive frames refine the image over several scans, whether they
+    // are Huffman or arithmetic coded and whether or not they are
+    // differential (refer to table B.1 in the spec)
+    pub fn is_progressive(&self) -> bool {
+        return matches!(self.frame_header.marker, Markers::SOF2 | Markers::SOF6 | Markers::SOF10 | Markers::SOF14)
+    }
+
+    // The quantization table a component was coded with. Its table must be
+    // defined before the first scan holding the component begins, and may
+    // be redefined afterwards for components in later scans (refer to
+    // B.2.4.1 in the spec). A frame without scans uses the last definition.
+    pub fn quantization_table(&self, fc: &FrameComponent) -> Result<&QuantizationTable, DecodeError> {
+        let selector = fc.quantization_table_selector;
+        let tables = self.scans
+            .iter()
+            .find(|scan| scan.scan_header.components.iter().any(|sc| sc.id == fc.id))
+            .map_or(&self.quantization_tables, |scan| &scan.quantization_tables);
+        return tables
+            .get(selector as usize)
+            .and_then(Option::as_ref)
+            .ok_or(DecodeError::MissingQuantizationTable { selector })
+    }
+
+    // Estimates the quality setting (1 to 100) of an encoder that scales the
+    // Annex K luminance table the way libjpeg does, from the table used by
+    // the first (luma) component. Returns None if that table is missing.
+    pub fn estimated_quality(&self) -> Option<u8> {
+        let table = match self.frame_header.components.first() {
+            Some(fc) => self.quantization_table(fc).ok()?,
+            None => self.quantization_tables[0].as_ref()?
+        };
+        let base = annex_k::quantization_table(&0)?;
+        // libjpeg scales each base element by scale / 100, so the average
+        // ratio between the two recovers the scale. Elements clamped to 1 or
+        // 255 no longer reflect the scale and are left out where possible.
+        let ratios: Vec<u32> = table.elements.iter()
+            .zip(base.elements.iter())
+            .filter(|(element, _)| **element > 1 && **element < 255)
+            .map(|(element, base_element)| u32::from(*element) * 100 / u32::from(*base_element))
+            .collect();
+        let scale: u32 = if ratios.is_empty() {
+            // Every element is clamped, as happens at qualities of 1 and 100
+            if table.elements[0] > 1 { 5000 } else { 0 }
+        }
+        else {
+            (ratios.iter().sum::<u32>() + ratios.len() as u32 / 2) / ratios.len() as u32
+        };
+        let quality: u32 = if scale <= 100 {
+            (200 - scale).div_ceil(2)
+        }
+        else {
+            (5000 + scale / 2) / scale
+        };
+        return Some(quality.clamp(1, 100) as u8)
+    }
+
+    // Classifies chroma subsampling by how many luma samples each chroma
+    // sample covers. The first component is taken as luma and every other
+    // component as chroma. Files without chroma return None.
+    pub fn sampling_summary(&self) -> Option<ChromaSubsampling> {
+        let (luma, chroma) = self.frame_header.components.split_first()?;
+        let first_chroma = chroma.first()?;
+        let same_chroma = chroma.iter().all(|component| {
+            component.horizontal_sample_factor == first_chroma.horizontal_sample_factor
+            && component.vertical_sample_factor == first_chroma.vertical_sample_factor
+        });
+        if !same_chroma
+        || first_chroma.horizontal_sample_factor == 0
+        || first_chroma.vertical_sample_factor == 0
+        || luma.horizontal_sample_factor % first_chroma.horizontal_sample_factor != 0
+        || luma.vertical_sample_factor % first_chroma.vertical_sample_factor != 0 {
+            return Some(ChromaSubsampling::Other)
+        }
+        let ratio = (
+            luma.horizontal_sample_factor / first_chroma.horizontal_sample_factor,
+            luma.vertical_sample_factor / first_chroma.vertical_sample_factor
+        );
+        return Some(match ratio {
+            (1, 1) => ChromaSubsampling::Y444,
+            (2, 1) => ChromaSubsampling::Y422,
+            (2, 2) => ChromaSubsampling::Y420,
+            (1, 2) => ChromaSubsampling::Y440,
+            (4, 1) => ChromaSubsampling::Y411,
+            _ => ChromaSubsampling::Other
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ChromaSubsampling {
+    Y444, // Full chroma resolution
+    Y422, // Half horizontal chroma resolution
+    Y420, // Half horizontal and vertical chroma resolution
+    Y440, // Half vertical chroma resolution
+    Y411, // Quarter horizontal chroma resolution
+    Other
+}
+
+// The decoding process a SOF marker selects (refer to table B.1 in the spec)
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CodingProcess {
+    Baseline,           // SOF0
+    ExtendedSequential, // SOF1
+    Progressive,        // SOF2
+    Lossless,           // SOF3
+    Differential,       // SOF5 through SOF7
+    Arithmetic,         // SOF9 through SOF15
+}
+
+#[derive(Default, Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub struct FrameHeader {
+    pub marker: u8,                     // SOF or DHP, determines algorithm to decode file
+    pub length: u16,                    // Lf
+    pub precision: u8,                  // P
+    pub total_vertical_lines: u16,      // Y
+    pub total_horizontal_lines: u16,    // X
+    pub total_components: u8,           // Nf
+    pub components: Vec<FrameComponent>
+}
+
+impl FrameHeader {
+    fn build(&mut self, length: &u16, marker: &u8, data: &Vec<u8>) -> Result<(), DecodeError> {
+        self.marker = *marker;
+        self.length = *length;
+        // P, Y, X and Nf take up the first 6 bytes
+        if usize::from(*length) != data.len() || data.len() < 6 {
+            return Err(DecodeError::SegmentLengthMismatch(*marker));
+        }
+        self.precision = data[0];
+        self.total_vertical_lines = u16::from_be_bytes([data[1],data[2]]);
+        self.total_horizontal_lines = u16::from_be_bytes([data[3],data[4]]);
+        self.total_components = data[5];
+        
+        // Ensure the length matches the total_components
+        // Each component is 3 bytes. Computed as usize since 3 * Nf
+        // overflows u8 for more than 85 components.
+        let component_length: usize = usize::from(self.total_components) * 3;
+        if component_length + 6 != data.len() {
+            return Err(DecodeError::SegmentLengthMismatch(*marker));
+        }
+        let component_chunks = data[6..component_length+6].chunks(3);
+        for component_bytes in component_chunks.into_iter() {
+            let mut component = FrameComponent::default();
+            component.build(&component_bytes.to_vec());
+            // Every later stage sizes its mcus from the factors, which range
+            // from 1 to 4 (refer to B.2.2 in the spec)
+            let (horizontal, vertical) = (component.horizontal_sample_factor, component.vertical_sample_factor);
+            if !(1..=4).contains(&horizontal) || !(1..=4).contains(&vertical) {
+                return Err(DecodeError::InvalidSamplingFactor { component: component.id, horizontal, vertical });
+            }
+            self.components.push(component);
+        }
+        return Ok(())
+    }
+
+    // Scan components refer to frame components by id (Cs matches Ci)
+    // rather than by position, and ids needn't start at 1 or be dense.
+    fn component_index(&self, id: &u8) -> Result<usize, DecodeError> {
+        return self.components
+            .iter()
+            .position(|component| component.id == *id)
+            .ok_or(DecodeError::UnknownScanComponent(*id))
+    }
+
+    // Size of a component's plane in samples. Components sampled below the
+    // max factors cover the frame's dimensions scaled down by the ratio,
+    // rounded up (refer to A.1.1 in the spec).
+    fn plane_size(&self, fc: &FrameComponent) -> (u16, u16) {
+        let (max_vertical_factor, max_horizontal_factor) = max_sampling_factors(&self.components);
+        let width = (u32::from(self.total_horizontal_lines) * u32::from(fc.horizontal_sample_factor)).div_ceil(u32::from(max_horizontal_factor));
+        let height = (u32::from(self.total_vertical_lines) * u32::from(fc.vertical_sample_factor)).div_ceil(u32::from(max_vertical_factor));
+        return (width as u16, height as u16)
+    }
+
+    pub fn component_info(&self) -> Vec<ComponentInfo> {
+        return self.components
+            .iter()
+            .map(|fc| {
+                let (width, height) = self.plane_size(fc);
+                ComponentInfo {
+                    id: fc.id,
+                    horizontal_sample_factor: fc.horizontal_sample_factor,
+                    vertical_sample_factor: fc.vertical_sample_factor,
+                    quantization_table_selector: fc.quantization_table_selector,
+                    width,
+                    height
+                }
+            })
+            .collect()
+    }
+
+    pub fn coding_process(&self) -> Option<CodingProcess> {
+        return match self.marker {
+            Markers::SOF0 => Some(CodingProcess::Baseline),
+            Markers::SOF1 => Some(CodingProcess::ExtendedSequential),
+            Markers::SOF2 => Some(CodingProcess::Progressive),
+            Markers::SOF3 => Some(CodingProcess::Lossless),
+            Markers::SOF5..=Markers::SOF7 => Some(CodingProcess::Differential),
+            Markers::SOF9..=Markers::SOF15 => Some(CodingProcess::Arithmetic),
+            _ => None
+        }
+    }
+}
+
+#[derive(Default, Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub struct FrameComponent {
+    pub id: u8,                         // Ci
+    pub horizontal_sample_factor: u8,   // Hi
+    pub vertical_sample_factor: u8,     // Vi
+    pub quantization_table_selector: u8 // Tqi
+}
+
+impl FrameComponent {
+    // Split byte into hi and lo u8 values.
+    // Hi: horizontal_sample_factor
+    // Lo: vertical_sample_factor
+    fn sample_factor(&mut self, byte: &u8) {
+        self.horizontal_sample_factor = byte >> 4;
+        self.vertical_sample_factor = byte & 0x0f;
+    }
+    fn build(&mut self, data: &Vec<u8>) {
+        if data.len() != 3 {
+            panic!("(FrameComponent::build) Byte data not a length of 3.");
+        }
+        self.id = data[0];
+        self.sample_factor(&data[1]);
+        self.quantization_table_selector = data[2];
+    }
+}
+
+// A frame component together with the size of its plane, for reassembling
+// planes without repeating the sampling arithmetic
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ComponentInfo {
+    pub id: u8,
+    pub horizontal_sample_factor: u8,
+    pub vertical_sample_factor: u8,
+    pub quantization_table_selector: u8,
+    pub width: u16,  // Samples per line of the plane
+    pub height: u16  // Lines in the plane
+}
+
+#[derive(Default, Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub struct Scan {
+    pub scan_header: ScanHeader,
+    // entropy coded segments are separated by RST markers whose intervals are defined by DRI
+    #[cfg_attr(feature = "json", serde(skip))]
+    pub entropy_coded_segments: Vec<u8>, // ECSi
+    // Byte offsets into entropy_coded_segments where each segment after the
+    // first begins. One offset is recorded per RSTn marker found in the scan.
+    pub restart_offsets: Vec<usize>,
+    // File offset of the first entropy coded byte, and the offsets into
+    // entropy_coded_segments of each 0xff that was stuffed with a 0x00 in
+    // the file. Together with restart_offsets they map a position in the
+    // entropy coded data back to the file for error messages.
+    pub data_offset: usize,
+    #[cfg_attr(feature = "json", serde(skip))]
+    pub stuffed_offsets: Vec<usize>,
+    // Huffman tables in effect when the scan began. Tables may be redefined
+    // between scans, so each scan keeps the definitions it was coded with.
+    // They are left out of the JSON dump since the frame lists them already.
+    #[cfg_attr(feature = "json", serde(skip))]
+    pub dc_huffman_tables: [Option<HuffmanTable>; 4],
+    #[cfg_attr(feature = "json", serde(skip))]
+    pub ac_huffman_tables: [Option<HuffmanTable>; 4],
+    // Likewise the quantization tables. A component uses the ones of the
+    // first scan it appears in (refer to Frame::quantization_table).
+    #[cfg_attr(feature = "json", serde(skip))]
+    pub quantization_tables: [Option<QuantizationTable>; 4],
+    // Arithmetic coding conditioning defined before the scan began, in the
+    // order the DAC segments gave it
+    #[cfg_attr(feature = "json", serde(skip))]
+    pub arithmetic_tables: Vec<ArithmeticTable>
+}
+
+impl Scan {
+    // Each stuffed 0xff was followed by a 0x00 in the file and each restart
+    // offset by a 2 byte RSTn marker, none of which were kept
+    fn file_offset(&self, entropy_idx: &usize) -> usize {
+        let stuffed = self.stuffed_offsets.partition_point(|offset| offset < entropy_idx);
+        let restarts = self.restart_offsets.partition_point(|offset| offset <= entropy_idx);
+        return self.data_offset + entropy_idx + stuffed + 2 * restarts
+    }
+}
+
+#[derive(Default, Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub struct ScanHeader {
+    pub length: u16,                     // Ls
+    pub total_components: u8,            // Ns
+    pub spectral_selection_start: u8,    // Ss
+    pub spectral_selection_end: u8,      // Se
+    pub successive_approximation_hi: u8, // Ah
+    pub successive_approximation_lo: u8, // Al
+    pub components: Vec<ScanComponent>,
+}
+
+impl ScanHeader {
+    // Split byte into hi and lo u8 values.
+    // Hi: successive_approximation_hi
+    // Lo: successive_approximation_lo
+    fn successive_approximation(&mut self, byte: &u8) {
+        self.successive_approximation_hi = byte >> 4;
+        self.successive_approximation_lo = byte & 0x0f;
+    }
+
+    fn build(&mut self, length: &u16, data: &Vec<u8>) -> Result<(), DecodeError> {
+        self.length = *length;
+        if usize::from(*length) != data.len() || data.is_empty() {
+            return Err(DecodeError::SegmentLengthMismatch(Markers::SOS));
+        }
+        self.total_components = data[0];
+        // A scan codes 1 to 4 components (refer to B.2.3 in the spec), so
+        // any other Ns can't be matched by a valid length
+        if !matches!(self.total_components, 1..=4) {
+            return Err(DecodeError::SegmentLengthMismatch(Markers::SOS));
+        }
+        // Ensure the length matches the total_components
+        // Each component is 2 bytes and there are 4 bytes of parameters.
+        // Computed as usize since 2 * Ns overflows u8 for more than 127
+        // components.
+        let component_length: usize = usize::from(self.total_components) * 2;
+        if component_length + 4 != data.len() {
+            return Err(DecodeError::SegmentLengthMismatch(Markers::SOS));
+        }
+        // Each component is 2 bytes
+        let component_chunks = data[1..component_length+1].chunks(2);
+        for component_bytes in component_chunks.into_iter() {
+            let mut component = ScanComponent::default();
+            component.build(&component_bytes.to_vec());
+            self.components.push(component);
+        }
+        // Ns takes up the first byte, so the parameters after the
+        // components start at 1 + component_length
+        self.spectral_selection_start = data[1 + component_length];
+        self.spectral_selection_end = data[2 + component_length];
+        self.successive_approximation(&data[3 + component_length]);
+        return Ok(())
+    }
+
+    // Sequential DCT scans always cover every coefficient in a single pass,
+    // so these fields have fixed values (refer to B.2.3 in the spec).
+    fn validate_sequential(&self) -> Result<(), DecodeError> {
+        if self.spectral_selection_start != 0
+        || self.spectral_selection_end != 63
+        || self.successive_approximation_hi != 0
+        || self.successive_approximation_lo != 0 {
+            return Err(DecodeError::InvalidScanParameters {
+                spectral_selection_start: self.spectral_selection_start,
+                spectral_selection_end: self.spectral_selection_end,
+                successive_approximation_hi: self.successive_approximation_hi,
+                successive_approximation_lo: self.successive_approximation_lo
+            });
+        }
+        return Ok(())
+    }
+
+    // Progressive scans code either the DC coefficients, of any number of
+    // components, or a band of AC coefficients of a single component. Each
+    // refinement scan adds one bit (refer to G.1.1.1.1 in the spec).
+    fn validate_progressive(&self) -> Result<(), DecodeError> {
+        let dc_scan: bool = self.spectral_selection_start == 0;
+        if (dc_scan && self.spectral_selection_end != 0)
+        || (!dc_scan && (self.spectral_selection_end < self.spectral_selection_start || self.spectral_selection_end > 63 || self.total_components != 1))
+        || (self.successive_approximation_hi != 0 && self.successive_approximation_lo + 1 != self.successive_approximation_hi)
+        || self.successive_approximation_lo > 13 {
+            return Err(DecodeError::InvalidScanParameters {
+                spectral_selection_start: self.spectral_selection_start,
+                spectral_selection_end: self.spectral_selection_end,
+                successive_approximation_hi: self.successive_approximation_hi,
+                successive_approximation_lo: self.successive_approximation_lo
+            });
+        }
+        return Ok(())
+    }
+}
+
+#[derive(Default, Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub struct ScanComponent {
+    pub id: u8,                    // Cs
+    pub dc_entropy_table_dest: u8, // Tdi
+    pub ac_entropy_table_dest: u8, // Tai
+    #[cfg_attr(feature = "json", serde(skip))]
+    prev_dc_coefficient: i16
+}
+
+impl ScanComponent {
+    fn entropy_table_dest(&mut self, byte: &u8) {
+        self.dc_entropy_table_dest = byte >> 4;
+        self.ac_entropy_table_dest = byte & 0x0f;
+    }
+    fn build(&mut self, data: &Vec<u8>) {
+        self.id = data[0];
+        self.entropy_table_dest(&data[1]);
+        self.prev_dc_coefficient = 0;
+    }
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub struct QuantizationTable {
+    pub length: u16,        // Lq
+    pub precision: u8,      // Pq
+    pub destination_id: u8, // Tq
+    #[cfg_attr(feature = "json", serde(serialize_with = "json::elements"))]
+    pub elements: [u16; 64] // Qi; limit 64 capacity
+}
+
+impl Default for QuantizationTable {
+    fn default() -> Self {
+        QuantizationTable {
+            length: 0,
+            precision: 0,
+            destination_id: 0,
+            elements: [0; 64]
+        }
+    }
+}
+
+impl QuantizationTable {
+    fn precision_and_destination_id(&mut self, byte: &u8) {
+        self.precision = byte >> 4;
+        self.destination_id = byte & 0x0f;
+    }
+
+    fn build(&mut self, length: &u16, data: &Vec<u8>) -> Result<(), DecodeError> {
+        self.length = *length;
+        // The length must also be the one the precision calls for
+        if usize::from(*length) != data.len() || data.is_empty() || data.len() != QuantizationTable::table_length(&data[0])? {
+            return Err(DecodeError::SegmentLengthMismatch(Markers::DQT));
+        }
+        self.precision_and_destination_id(&data[0]);
+        // There are only four destinations
+        if self.destination_id > 3 {
+            return Err(DecodeError::InvalidQuantizationTable);
+        }
+        // Elements keep the zigzag order they have in the file, matching the
+        // order decode_block produces coefficients in.
+        // 8-bit tables (Pq = 0) store one byte per element, 16-bit tables
+        // (Pq = 1) store two big-endian bytes per element.
+        if self.precision == 0 {
+            for (idx, element) in data[1..].iter().enumerate() {
+                self.elements[idx] = u16::from(*element);
+            }
+        }
+        else {
+            for (idx, element) in data[1..].chunks(2).enumerate() {
+                self.elements[idx] = u16::from_be_bytes([element[0], element[1]]);
+            }
+        }
+        return Ok(())
+    }
+
+    // Bytes taken up in a DQT segment by a table starting with the given
+    // precision and destination byte, including that byte itself.
+    fn table_length(precision_and_destination_id: &u8) -> Result<usize, DecodeError> {
+        return match precision_and_destination_id >> 4 {
+            0 => Ok(1 + 64),
+            1 => Ok(1 + 128),
+            _ => Err(DecodeError::InvalidQuantizationTable)
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub struct HuffmanTable {
+    pub length: u16,                   // Lh
+    pub class: u8,                     // Tc
+    pub destination_id: u8,            // Th
+    pub huffman_size_lengths: [u8; 16], // Li; 1 >= i <= 16
+    pub huffman_values: Vec<u8>,       //Vij; HUFFVAL; 0 >= j <= 255
+    // Below are used for decoding procedure
+    #[cfg_attr(feature = "json", serde(skip))]
+    pub mincode: [u16; 16],
+    #[cfg_attr(feature = "json", serde(skip))]
+    pub maxcode: [Option<u16>; 16],
+    #[cfg_attr(feature = "json", serde(skip))]
+    pub valptr: [usize; 16],
+    // (symbol, code length) for every HUFFMAN_LOOKUP_BITS-bit prefix that
+    // starts with a code no longer than that. A length of 0 means the
+    // prefix starts a longer code.
+    #[cfg_attr(feature = "json", serde(skip))]
+    pub lookup: Vec<(u8, u8)>,
+}
+
+// Codes up to this length are decoded with a single table lookup. Longer
+// codes are rare in practice and fall back to the bit by bit walk.
+const HUFFMAN_LOOKUP_BITS: u8 = 9;
+
+impl HuffmanTable {
+    fn class_and_destination_id(&mut self, byte: &u8) {
+        self.class = byte >> 4;
+        self.destination_id = byte & 0x0f;
+    }
+
+    // The output table is referred to as HUFFSIZE in the spec
+    fn generate_size_table(& self) -> Vec<u8> {
+        let mut huffman_sizes: Vec<u8> = Vec::new();
+        for (size, size_len) in self.huffman_size_lengths.iter().enumerate() {
+            for _ in 0..*size_len {
+                huffman_sizes.push((size + 1).try_into().unwrap());
+            }
+        }
+        return huffman_sizes
+    }
+
+    // The output table is referred to as HUFFCODE in the spec
+    fn generate_code_table(& self, huffman_sizes: &Vec<u8>) -> Result<Vec<u16>, DecodeError> {
+        let mut huffman_codes: Vec<u16> = Vec::new();
+        let mut code: u32 = 0;
+        // A table may define no codes at all, for a destination that no
+        // scan uses
+        let Some(mut prev_size) = huffman_sizes.first().copied() else {
+            return Ok(huffman_codes)
+        };
+        for size in huffman_sizes.iter() {
+            while size != &prev_size {
+                code <<= 1;
+                prev_size += 1;
+            }
+            // Every code must fit within its size (refer to C.2 in the
+            // spec). A code that doesn't means the counts describe an
+            // over-subscribed tree.
+            if code >= 1 << size {
+                return Err(DecodeError::InvalidHuffmanTable);
+            }
+            huffman_codes.push(code as u16);
+            code += 1;
+        }
+        return Ok(huffman_codes)
+    }
+
+    fn decoder_tables(&mut self, huffman_codes: &Vec<u16>) {
+        let mut j: usize = 0;
+        for i in 0..16 {
+            // Sizes without codes, which is every size of an empty table,
+            // have no code range to match against
+            if self.huffman_size_lengths[i] == 0 || j >= huffman_codes.len() {
+                self.maxcode[i] = None;
+            }
+            else {
+                self.valptr[i] = j;
+                self.mincode[i] = *huffman_codes.get(j).unwrap();
+                j += usize::from(self.huffman_size_lengths[i] - 1);
+                self.maxcode[i] = Some(*huffman_codes.get(j).unwrap());
+                j += 1;
+            }
+        }
+    }
+
+    fn lookup_table(&mut self, huffman_sizes: &Vec<u8>, huffman_codes: &Vec<u16>) {
+        self.lookup = vec![(0, 0); 1 << HUFFMAN_LOOKUP_BITS];
+        for (k, size) in huffman_sizes.iter().enumerate() {
+            if *size > HUFFMAN_LOOKUP_BITS {
+                break;
+            }
+            // Every prefix beginning with this code maps to it
+            let unused_bits = HUFFMAN_LOOKUP_BITS - size;
+            let first = usize::from(huffman_codes[k]) << unused_bits;
+            for entry in self.lookup[first..first + (1 << unused_bits)].iter_mut() {
+                *entry = (self.huffman_values[k], *size);
+            }
+        }
+    }
+
+    fn build(&mut self, length: &u16, data: &Vec<u8>) -> Result<(), DecodeError> {
+        self.length = *length;
+        // The counts are followed by one value per code
+        if usize::from(*length) != data.len()
+        || data.len() < 17
+        || data.len() != 17 + data[1..17].iter().map(|count| usize::from(*count)).sum::<usize>() {
+            return Err(DecodeError::SegmentLengthMismatch(Markers::DHT));
+        }
+        self.class_and_destination_id(&data[0]);
+        // Tables are either DC (0) or AC (1), and extended sequential files
+        // may use up to four destinations of each
+        if self.class > 1 || self.destination_id > 3 {
+            return Err(DecodeError::InvalidHuffmanTable);
+        }
+        // Put all huffman size lengths into huffman_size_lengths vector
+        for (idx, size) in data[1..17].iter().enumerate() {
+            self.huffman_size_lengths[idx] = *size;
+        }
+        // Put all huffman values into huffman_values vector
+        self.huffman_values = data[17..].to_vec();
+        // Decode the huffman table
+        let sizes: Vec<u8> =  self.generate_size_table();
+        let codes: Vec<u16> = self.generate_code_table(&sizes)?;
+        self.decoder_tables(&codes);
+        self.lookup_table(&sizes, &codes);
+        return Ok(())
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub struct ArithmeticTable {
+    pub length: u16,        // La
+    pub class: u8,          // Tc
+    pub destination_id: u8, // Tb
+    pub value: u8           // Cs
+}
+
+impl ArithmeticTable {
+    fn class_and_destination_id(&mut self, byte: &u8) {
+        self.class = byte >> 4;
+        self.destination_id = byte & 0x0f;
+    }
+
+    // A DAC segment may condition several tables. data holds the 2 bytes
+    // of one of them.
+    fn build(&mut self, length: &u16, data: &[u8]) -> Result<(), DecodeError> {
+        self.length = *length;
+        if data.len() != 2 {
+            return Err(DecodeError::SegmentLengthMismatch(Markers::DAC));
+        }
+        self.class_and_destination_id(&data[0]);
+        self.value = data[1];
+        return Ok(())
+    }
+}
+
+#[derive(Default, Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub struct RestartInterval {
+    pub length: u16,  // Lr
+    pub interval: u16 // Ri
+}
+
+impl RestartInterval {
+    fn build(&mut self, length: &u16, data: &Vec<u8>) -> Result<(), DecodeError> {
+        // Ri is the only parameter
+        if data.len() != usize::from(*length) || data.len() != 2 {
+            return Err(DecodeError::SegmentLengthMismatch(Markers::DRI));
+        }
+        self.length = *length;
+        self.interval = u16::from_be_bytes([data[0],data[1]]);
+        return Ok(())
+    }
+}
+
+#[derive(Default, Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub struct Comment {
+    pub length: u16,           // Lc
+    #[cfg_attr(feature = "json", serde(rename = "text", serialize_with = "json::text"))]
+    pub comment_bytes: Vec<u8> // Cmi
+}
+
+impl Comment {
+    fn build(&mut self, length: &u16, data: &Vec<u8>) -> Result<(), DecodeError> {
+        self.length = *length;
+        if usize::from(*length) != data.len() {
+            return Err(DecodeError::SegmentLengthMismatch(Markers::COM));
+        }
+        self.comment_bytes = data[0..].to_vec();
+        return Ok(())
+    }
+
+    // Comments are usually text. Invalid UTF-8 is replaced rather than
+    // rejected, and some encoders NUL-terminate, so anything from the first
+    // NUL onwards is dropped.
+    pub fn as_str(&self) -> Cow<'_, str> {
+        return text_until_nul(&self.comment_bytes)
+    }
+}
+
+fn text_until_nul(bytes: &[u8]) -> Cow<'_, str> {
+    let end = bytes.iter().position(|byte| *byte == 0).unwrap_or(bytes.len());
+    return String::from_utf8_lossy(&bytes[..end])
+}
+
+#[derive(Default, Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub struct ApplicationData {
+    pub marker: u8,
+    pub offset: usize,            // File offset of the segment's 0xff byte
+    pub length: u16,              // Lp
+    #[cfg_attr(feature = "json", serde(rename = "identifier", serialize_with = "json::identifier"))]
+    pub application_data: Vec<u8> // Api
+}
+
+impl ApplicationData {
+    fn build(&mut self, marker: &u8, length: &u16, data: &Vec<u8>) -> Result<(), DecodeError> {
+        self.length = *length;
+        if usize::from(*length) != data.len() {
+            return Err(DecodeError::SegmentLengthMismatch(*marker));
+        }
+        self.marker = *marker;
+        self.application_data = data[0..].to_vec();
+        return Ok(())
+    }
+
+    pub fn identifier(&self) -> Option<&str> {
+        return application_identifier(&self.application_data)
+    }
+}
+
+impl ApplicationData {
+    // MPF segments follow "MPF\0" with a TIFF style header giving the byte
+    // order and the offset of the MP Index IFD. Its MP Entry tag (0xb002)
+    // points to 16 bytes per image: attributes, size, offset and two
+    // dependent image numbers (refer to 5.2 in CIPA DC-007). Offsets count
+    // from the start of that header, except for the primary image's, which
+    // is 0 since it starts the file.
+    fn mpf_images(&self) -> Option<Vec<Range<usize>>> {
+        let data = &self.application_data;
+        if self.marker != Markers::APP2 || !data.starts_with(b"MPF\0") {
+            return None
+        }
+        let header = &data[4..];
+        let big_endian = match header.get(..4)? {
+            b"MM\0*" => true,
+            b"II*\0" => false,
+            _ => return None
+        };
+        let read_u16 = |at: usize| -> Option<u16> {
+            let bytes: [u8; 2] = header.get(at..at.checked_add(2)?)?.try_into().ok()?;
+            return Some(if big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+        };
+        let read_u32 = |at: usize| -> Option<usize> {
+            let bytes: [u8; 4] = header.get(at..at.checked_add(4)?)?.try_into().ok()?;
+            return Some(if big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) } as usize)
+        };
+        let ifd = read_u32(4)?;
+        let total_tags = read_u16(ifd)?;
+        // (offset, total images) of the MP entries. Their count is in bytes.
+        let mut entries: Option<(usize, usize)> = None;
+        for tag_idx in 0..total_tags as usize {
+            let tag = ifd + 2 + 12 * tag_idx;
+            if read_u16(tag)? == 0xb002 {
+                entries = Some((read_u32(tag + 8)?, read_u32(tag + 4)? / 16));
+            }
+        }
+        let (entries_offset, total_images) = entries?;
+        // The 0xff, the marker, the length and "MPF\0" precede the header
+        let header_offset = self.offset + 8;
+        let mut images: Vec<Range<usize>> = Vec::new();
+        for image_idx in 0..total_images {
+            let entry = entries_offset + 16 * image_idx;
+            let size = read_u32(entry + 4)?;
+            let offset = read_u32(entry + 8)?;
+            let start = if offset == 0 { 0 } else { header_offset.saturating_add(offset) };
+            images.push(start..start.saturating_add(size));
+        }
+        return Some(images)
+    }
+}
+
+// Most APP segments begin with a NUL-terminated ASCII string naming the
+// application, such as "JFIF" or "Exif". Adobe APP14 segments have no
+// terminator, but the version following "Adobe" starts with a 0 byte.
+fn application_identifier(data: &[u8]) -> Option<&str> {
+    let end = data.iter().position(|byte| *byte == 0)?;
+    let identifier = &data[..end];
+    if identifier.is_empty() || !identifier.iter().all(|byte| byte.is_ascii_graphic()) {
+        return None
+    }
+    return core::str::from_utf8(identifier).ok()
+}
+
+// Transform flag of the Adobe APP14 segment. It describes how the encoder
+// transformed the components before compressing them.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub enum ColorTransform {
+    Unknown, // 0: RGB for 3 components, CMYK for 4
+    YCbCr,   // 1
+    Ycck,    // 2
+}
+
+impl ApplicationData {
+    // APP14 segments written by Adobe software start with "Adobe" followed by
+    // a version, two flag words and the transform byte.
+    pub fn adobe_color_transform(&self) -> Option<ColorTransform> {
+        let data = &self.application_data;
+        if self.marker != Markers::APP14 || data.len() < 12 || !data.starts_with(b"Adobe") {
+            return None
+        }
+        return match data[11] {
+            0 => Some(ColorTransform::Unknown),
+            1 => Some(ColorTransform::YCbCr),
+            2 => Some(ColorTransform::Ycck),
+            _ => None
+        }
+    }
+
+    // JFXX APP0 segments carry a thumbnail in one of three formats, given by
+    // the extension code following "JFXX\0":
+    // - 0x10: a complete JPEG stream, decoded here with the given options
+    // - 0x11: width, height, a 256 entry RGB palette and 1 byte per pixel
+    // - 0x13: width, height and 3 bytes (R, G, B) per pixel
+    // Returns None for other segments.
+    pub fn jfxx_thumbnail(&self, options: &DecodeOptions) -> Result<Option<Image>, DecodeError> {
+        let data = &self.application_data;
+        if self.marker != Markers::APP0 || data.len() < 6 || !data.starts_with(b"JFXX\0") {
+            return Ok(None)
+        }
+        let extension_code = data[5];
+        let thumbnail = &data[6..];
+        if extension_code == 0x10 {
+            return decode_image(thumbnail, options, None).map(Some)
+        }
+        if thumbnail.len() < 2 {
+            return Err(DecodeError::SegmentLengthMismatch(Markers::APP0));
+        }
+        let width = u16::from(thumbnail[0]);
+        let height = u16::from(thumbnail[1]);
+        let total_pixels = width as usize * height as usize;
+        let pixels: Vec<u8> = match extension_code {
+            0x11 => {
+                let palette = thumbnail.get(2..2 + 768).ok_or(DecodeError::SegmentLengthMismatch(Markers::APP0))?;
+                let indices = thumbnail.get(770..770 + total_pixels).ok_or(DecodeError::SegmentLengthMismatch(Markers::APP0))?;
+                indices.iter().flat_map(|idx| palette[*idx as usize * 3..*idx as usize * 3 + 3].iter().copied()).collect()
+            },
+            0x13 => thumbnail.get(2..2 + total_pixels * 3).ok_or(DecodeError::SegmentLengthMismatch(Markers::APP0))?.to_vec(),
+            _ => return Ok(None)
+        };
+        let image = Image {
+            width,
+            height,
+            total_components: 3,
+            format: PixelFormat::Rgb8,
+            pixels,
+            truncated: false
+        };
+        return Ok(Some(match options.output {
+            Some(format) if format != image.format => convert_pixels(&image, &format),
+            _ => image
+        }))
+    }
+}
+
+#[derive(Default, Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub struct NumberOfLines {
+    pub length: u16,     // Ld
+    pub total_lines: u16 // NL
+}
+
+impl NumberOfLines {
+    fn build(&mut self, length: &u16, data: &Vec<u8>) -> Result<(), DecodeError> {
+        // NL is the only parameter
+        if data.len() != usize::from(*length) || data.len() != 2 {
+            return Err(DecodeError::SegmentLengthMismatch(Markers::DNL));
+        }
+        self.length = *length;
+        self.total_lines = u16::from_be_bytes([data[0],data[1]]);
+        return Ok(())
+    }
+}
+
+#[derive(Default, Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub struct ExpandReference {
+    pub length: u16,             // Le
+    pub expand_horizontally: u8, // Eh
+    pub expand_vertically: u8    // Ev
+}
+
+impl ExpandReference {
+    fn expand_horizontally_and_vertically(&mut self, byte: &u8) {
+        self.expand_horizontally = byte >> 4;
+        self.expand_vertically = byte & 0x0f;
+    }
+    fn build(&mut self, length: &u16, data: &Vec<u8>) -> Result<(), DecodeError> {
+        self.length = *length;
+        // Eh and Ev share the only byte
+        if usize::from(*length) != data.len() || data.len() != 1 {
+            return Err(DecodeError::SegmentLengthMismatch(Markers::EXP));
+        }
+        self.expand_horizontally_and_vertically(&data[0]);
+        return Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum ReadStage {
+    Marker,
+    Length,
+    Segment,
+    DHTSegment,
+    DQTSegment,
+    SegmentEnd, // An APPn or COM segment waiting on the byte after it
+    Scan,
+    Trailer,  // After EOI
+    Finished  // After the first scan header when only headers are read
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    UnexpectedRestartMarker(u8), // RSTn found outside of a scan
+    RestartMarkerOutOfSequence { expected: u8, found: u8 },
+    MissingRestartMarker(u8),    // A restart interval ended without its RSTn marker (strict mode)
+    TruncatedStream,             // Entropy coded data ended before decoding finished
+    InvalidHuffmanCode,          // No huffman code matched after reading 16 bits
+    InvalidHuffmanTable,         // DHT code counts do not describe a valid prefix code
+    InvalidQuantizationTable,    // DQT precision or destination is out of range
+    InvalidCoefficient,          // A coefficient is too long for the precision or runs past the end of its block
+    NotAJpeg,                    // The file doesn't begin with an SOI marker
+    MissingFrameHeader,          // No SOF segment was found
+    HierarchicalNotSupported,    // DHP found; multi-frame hierarchical files can't be decoded
+    BlockCountMismatch { expected: usize, got: usize }, // Decoded blocks do not fill the frame's MCUs
+    Unsupported(CodingProcess),  // Only sequential DCT frames can be decoded
+    InvalidMarker(u8),           // A byte other than 0xff was found where a marker should start (strict mode)
+    UnknownMarker(u8),           // Reserved marker found (strict mode)
+    SegmentLengthMismatch(u8),   // Segment length disagrees with its contents (strict mode)
+    MissingEndOfImage,           // File ended without an EOI marker (strict mode)
+    MissingHuffmanTable { class: u8, destination_id: u8 }, // A scan refers to a table that was never defined
+    MissingQuantizationTable { selector: u8 }, // A frame component refers to a table that was never defined
+    UnknownScanComponent(u8),    // A scan refers to a component id the frame doesn't define
+    UnknownComponent(u8),        // Decoder::decode_component was given an id the frame doesn't define
+    UnsupportedComponentCount(u8), // Only 1, 2 and 3 component frames can be converted to pixels
+    UnsupportedPrecision(u8),    // Only 8 and 12-bit samples can be decoded
+    InvalidSamplingFactor { component: u8, horizontal: u8, vertical: u8 }, // Hi or Vi is outside 1 to 4
+    InvalidScanParameters {      // Ss, Se, Ah or Al don't hold the values sequential scans require
+        spectral_selection_start: u8,
+        spectral_selection_end: u8,
+        successive_approximation_hi: u8,
+        successive_approximation_lo: u8
+    },
+    BufferTooSmall { required: usize, got: usize }, // Output buffer can't hold the decoded image
+    DimensionsTooLarge { width: u16, height: u16 },  // Frame exceeds the limits set in DecodeOptions
+    NumberOfLinesMismatch { frame: u16, lines: u16 }, // DNL disagrees with a nonzero SOF height, or is 0 (strict mode)
+    #[cfg(feature = "std")]
+    Io(std::io::Error),          // Reading the file failed
+    At { offset: usize, scan: Option<usize>, error: Box<DecodeError> }, // Where in the file another error occurred
+}
+
+impl DecodeError {
+    // Records where the error occurred. Only the innermost position is kept.
+    fn at(self, offset: usize, scan: Option<usize>) -> DecodeError {
+        return match self {
+            DecodeError::At { .. } => self,
+            error => DecodeError::At { offset, scan, error: Box::new(error) }
+        }
+    }
+
+    // The error without its position
+    pub fn kind(&self) -> &DecodeError {
+        return match self {
+            DecodeError::At { error, .. } => error.kind(),
+            error => error
+        }
+    }
+}
+
+impl core::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        return match self {
+            DecodeError::UnexpectedRestartMarker(marker) => write!(f, "restart marker {:#04x} outside of a scan", marker),
+            DecodeError::RestartMarkerOutOfSequence { expected, found } => write!(f, "expected restart marker {:#04x} but found {:#04x}", expected, found),
+            DecodeError::MissingRestartMarker(marker) => write!(f, "restart marker {:#04x} is missing", marker),
+            DecodeError::TruncatedStream => write!(f, "entropy coded data ended early"),
+            DecodeError::InvalidHuffmanCode => write!(f, "invalid Huffman code"),
+            DecodeError::InvalidQuantizationTable => write!(f, "invalid quantization table"),
+            DecodeError::InvalidHuffmanTable => write!(f, "invalid Huffman table"),
+            DecodeError::InvalidCoefficient => write!(f, "invalid coefficient"),
+            DecodeError::NotAJpeg => write!(f, "not a JPEG file"),
+            DecodeError::MissingFrameHeader => write!(f, "no frame header"),
+            DecodeError::HierarchicalNotSupported => write!(f, "hierarchical files are not supported"),
+            DecodeError::BlockCountMismatch { expected, got } => write!(f, "expected {} blocks but decoded {}", expected, got),
+            DecodeError::Unsupported(process) => write!(f, "{:?} coding is not supported", process),
+            DecodeError::InvalidMarker(byte) => write!(f, "expected a marker but found {:#04x}", byte),
+            DecodeError::UnknownMarker(marker) => write!(f, "reserved marker {:#04x}", marker),
+            DecodeError::SegmentLengthMismatch(marker) => write!(f, "length of segment {:#04x} does not match its contents", marker),
+            DecodeError::MissingEndOfImage => write!(f, "no end of image marker"),
+            DecodeError::MissingHuffmanTable { class, destination_id } => write!(f, "missing {} Huffman table {}", if *class == 0 { "DC" } else { "AC" }, destination_id),
+            DecodeError::MissingQuantizationTable { selector } => write!(f, "missing quantization table {}", selector),
+            DecodeError::UnknownScanComponent(id) => write!(f, "scan refers to unknown component {}", id),
+            DecodeError::UnknownComponent(id) => write!(f, "no component with id {}", id),
+            DecodeError::UnsupportedComponentCount(count) => write!(f, "{} component images can't be converted to pixels", count),
+            DecodeError::UnsupportedPrecision(precision) => write!(f, "{}-bit samples are not supported", precision),
+            DecodeError::InvalidSamplingFactor { component, horizontal, vertical } => write!(f, "component {} has invalid sampling factors {}x{}", component, horizontal, vertical),
+            DecodeError::InvalidScanParameters { spectral_selection_start, spectral_selection_end, successive_approximation_hi, successive_approximation_lo } =>
+                write!(f, "invalid scan parameters Ss={} Se={} Ah={} Al={}", spectral_selection_start, spectral_selection_end, successive_approximation_hi, successive_approximation_lo),
+            DecodeError::BufferTooSmall { required, got } => write!(f, "buffer holds {} bytes but {} are needed", got, required),
+            DecodeError::DimensionsTooLarge { width, height } => write!(f, "{}x{} image exceeds the size limits", width, height),
+            DecodeError::NumberOfLinesMismatch { frame, lines } => write!(f, "DNL gives {} lines but the frame header gives {}", lines, frame),
+            #[cfg(feature = "std")]
+            DecodeError::Io(error) => write!(f, "{}", error),
+            DecodeError::At { offset, scan: Some(scan), error } => write!(f, "{} at offset {:#X} in scan {}", error, offset, scan),
+            DecodeError::At { offset, scan: None, error } => write!(f, "{} at offset {:#X}", error, offset)
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        return match self {
+            DecodeError::Io(error) => Some(error),
+            DecodeError::At { error, .. } => error.source(),
+            _ => None
+        }
+    }
+}
+
+// Lets file and reader errors pass through ? alongside decoding errors
+#[cfg(feature = "std")]
+impl From<std::io::Error> for DecodeError {
+    fn from(error: std::io::Error) -> DecodeError {
+        return DecodeError::Io(error)
+    }
+}
+
+// Decodes a file into (pixels, width, height), the pixels being 8-bit RGB:
+// rows top to bottom, each pixel's R, G and B bytes left to right, with no
+// padding between rows, so pixel (x, y) starts at 3 * (y * width + x).
+// Grayscale images have their sample repeated as R, G and B.
+pub fn decode_rgb8(bytes: &[u8]) -> Result<(Vec<u8>, u16, u16), DecodeError> {
+    let image = Decoder::new(bytes).with_output(PixelFormat::Rgb8).decode()?;
+    return Ok((image.pixels, image.width, image.height))
+}
+
+// Reads everything the reader has, such as an open file, and decodes it
+// with the default options. The whole file is needed before decoding can
+// begin, so this is Decoder::new on the bytes read.
+#[cfg(feature = "std")]
+pub fn decode_from_reader<R: std::io::Read>(mut reader: R) -> Result<Image, DecodeError> {
+    let mut bytes: Vec<u8> = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    return Decoder::new(&bytes).decode()
+}
+
+// A read-only view of one marker segment in a file
+#[derive(Debug)]
+pub struct Segment<'a> {
+    pub marker: u8,
+    pub offset: usize,      // Position of the segment's 0xff byte
+    pub length: u16,        // Length parameter, or 0 for markers without one
+    pub payload: &'a [u8]   // Segment data following the length parameter
+}
+
+pub struct Segments<'a> {
+    bytes: &'a [u8],
+    position: usize
+}
+
+// Walks every marker segment of a file without decoding anything. Entropy
+// coded data is skipped over, stepping past stuffed 0xff00 bytes, so only
+// real markers (including RSTn) are reported. Nothing is validated, which
+// suits tools that inspect damaged files, such as for forensics.
+pub fn segments(bytes: &[u8]) -> Segments<'_> {
+    return Segments { bytes, position: 0 }
+}
+
+impl<'a> Iterator for Segments<'a> {
+    type Item = Segment<'a>;
+
+    fn next(&mut self) -> Option<Segment<'a>> {
+        let bytes = self.bytes;
+        // Find the next 0xff that starts a marker. Outside of a scan any
+        // other byte is stray padding. Inside one it is image data, where
+        // 0xff is always stuffed with a following 0x00.
+        let mut offset = self.position;
+        loop {
+            while bytes.get(offset)? != &Markers::MRK {
+                offset += 1;
+            }
+            // Sequential 0xff values are treated as one
+            let mut marker_idx = offset + 1;
+            while bytes.get(marker_idx)? == &Markers::MRK {
+                marker_idx += 1;
+            }
+            if bytes[marker_idx] == Markers::ESC {
+                offset = marker_idx + 1;
+                continue;
+            }
+            offset = marker_idx - 1;
+            break;
+        }
+        let marker = bytes[offset + 1];
+        match marker {
+            Markers::SOI | Markers::EOI | Markers::TEM | Markers::RST0..=Markers::RST7 => {
+                // These markers stand alone without a length parameter
+                self.position = offset + 2;
+                return Some(Segment { marker, offset, length: 0, payload: &[] })
+            },
+            _ => {
+                let length_bytes = bytes.get(offset + 2..offset + 4)?;
+                let length = u16::from_be_bytes([length_bytes[0], length_bytes[1]]);
+                let end = (offset + 2 + length as usize).min(bytes.len());
+                let payload = bytes.get(offset + 4..end).unwrap_or(&[]);
+                self.position = end;
+                return Some(Segment { marker, offset, length, payload })
+            }
+        }
+    }
+}
+
+// Returns (width, height) from the first SOF segment. Only the markers up
+// to it are walked and nothing else is parsed, which makes this the
+// cheapest way to size an image.
+pub fn decode_dimensions(bytes: &[u8]) -> Result<(u16, u16), DecodeError> {
+    if !bytes.starts_with(&[Markers::MRK, Markers::SOI]) {
+        return Err(DecodeError::NotAJpeg);
+    }
+    let frame_header = segments(bytes)
+        .find(|segment| matches!(
+            segment.marker,
+            Markers::SOF0..=Markers::SOF3 | Markers::SOF5..=Markers::SOF7
+            | Markers::SOF9..=Markers::SOF11 | Markers::SOF13..=Markers::SOF15
+        ))
+        .ok_or(DecodeError::MissingFrameHeader)?;
+    // P comes first, followed by Y and X
+    let Some(&[_, y_hi, y_lo, x_hi, x_lo]) = frame_header.payload.get(..5) else {
+        return Err(DecodeError::SegmentLengthMismatch(frame_header.marker));
+    };
+    return Ok((u16::from_be_bytes([x_hi, x_lo]), u16::from_be_bytes([y_hi, y_lo])))
+}
+
+// Something a Parser has finished reading. The segment's contents have been
+// added to the parser's frame by the time its event is returned.
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    Segment { marker: u8, offset: usize }, // offset is where the marker's 0xff was read
+    EndOfImage
+}
+
+// The marker segment state machine. Bytes can be fed in chunks of any size,
+// such as packets arriving from a network, and reading picks up wherever the
+// previous chunk ended. feed reports each segment as it completes, frame
+// shows what has been read so far (the dimensions, say, once the SOF
+// segment's event arrives), and finish ends the file. decode_frame then
+// decodes the frame finish returns.
+#[derive(Debug)]
+pub struct Parser {
+    options: DecodeOptions,
+    headers_only: bool,
+    stage: ReadStage,
+    position: usize,      // File offset of the next byte to be read
+    marker_offset: usize, // File offset of the current marker's 0xff
+    current_marker_bytes: [Option<u8>; 2], // Identify segment to construct based on marker
+    segment_length_bytes: [Option<u8>; 2], // Used for bounds checking
+    segment_length: u16,
+    segment_data: Vec<u8>, // Used to build any segment struct
+    frame: Frame,
+    dht_table_length: u16,
+    resyncing: bool, // Skipping stray bytes while looking for a marker
+    found_end_of_image: bool
+}
+
+impl Parser {
+    // When headers_only is set, parsing stops at the first scan header so
+    // that none of the entropy coded data is read.
+    pub fn new(headers_only: &bool, options: &DecodeOptions) -> Parser {
+        return Parser {
+            options: *options,
+            headers_only: *headers_only,
+            stage: ReadStage::Marker,
+            position: 0,
+            marker_offset: 0,
+            current_marker_bytes: [None; 2],
+            segment_length_bytes: [None; 2],
+            segment_length: 0,
+            segment_data: Vec::new(),
+            frame: Frame::default(),
+            dht_table_length: 17,
+            resyncing: false,
+            found_end_of_image: false
+        }
+    }
+
+    // Returns an event for each segment completed by these bytes
+    pub fn feed(&mut self, bytes: &[u8]) -> Result<Vec<Event>, DecodeError> {
+        let mut events: Vec<Event> = Vec::new();
+        for (idx, byte) in bytes.iter().enumerate() {
+            match self.stage {
+                ReadStage::Finished => break,
+                ReadStage::Trailer => {
+                    // Anything after EOI, such as appended thumbnails or
+                    // the other images of an MPO file, is kept aside
+                    // rather than parsed.
+                    self.frame.trailer.extend(&bytes[idx..]);
+                    self.position += bytes.len() - idx;
+                    break;
+                },
+                _ => {}
+            }
+            self.read_byte(byte, &mut events).map_err(|error| error.at(self.position, None))?;
+            self.position += 1;
+        }
+        return Ok(events)
+    }
+
+    // The segments read so far, such as headers that arrived before the
+    // rest of the file
+    pub fn frame(&self) -> &Frame {
+        return &self.frame
+    }
+
+    // Ends the file and returns everything that was read
+    pub fn finish(mut self) -> Result<Frame, DecodeError> {
+        if self.position < 2 {
+            return Err(DecodeError::NotAJpeg.at(self.position, None));
+        }
+        if matches!(self.stage, ReadStage::SegmentEnd) {
+            // The file ended right after the segment
+            self.build_segment(&mut Vec::new()).map_err(|error| error.at(self.position, None))?;
+        }
+        if !self.found_end_of_image && !matches!(self.stage, ReadStage::Finished) {
+            if self.options.strict {
+                return Err(DecodeError::MissingEndOfImage.at(self.position, None));
+            }
+            warn!("(Parser::finish) warning: file ended without an EOI marker.");
+        }
+        return Ok(self.frame)
+    }
+
+    // Builds the struct for a segment once all of its data has been
+    // collected, and gets ready for the next marker or the scan data that
+    // follows SOS
+    fn build_segment(&mut self, events: &mut Vec<Event>) -> Result<(), DecodeError> {
+        // DRI, DNL and EXP segments have a fixed size. Any bytes past it are
+        // discarded in lenient mode, like those left over in DHT and DQT
+        // segments, while too few bytes can't be read at all.
+        let fixed_length: Option<usize> = match self.current_marker_bytes[1] {
+            Some(Markers::DRI | Markers::DNL) => Some(2),
+            Some(Markers::EXP) => Some(1),
+            _ => None
+        };
+        if let Some(fixed_length) = fixed_length.filter(|fixed_length| self.segment_data.len() > *fixed_length) {
+            let marker = self.current_marker_bytes[1].unwrap();
+            if self.options.strict {
+                return Err(DecodeError::SegmentLengthMismatch(marker));
+            }
+            warn!("(Parser::build_segment) warning: segment {:02x?} has {} bytes of unused data. Discarding them.", marker, self.segment_data.len() - fixed_length);
+            self.segment_data.truncate(fixed_length);
+            self.segment_length = fixed_length as u16;
+        }
+        if (self.current_marker_bytes[1] >= Some(Markers::SOF0) 
+        && self.current_marker_bytes[1] <= Some(Markers::SOF3))
+        || (self.current_marker_bytes[1] >= Some(Markers::SOF5) 
+        && self.current_marker_bytes[1] <= Some(Markers::SOF7))
+        || (self.current_marker_bytes[1] >= Some(Markers::SOF9) 
+        && self.current_marker_bytes[1] <= Some(Markers::SOF11))
+        || (self.current_marker_bytes[1] >= Some(Markers::SOF13) 
+        && self.current_marker_bytes[1] <= Some(Markers::SOF15))
+        || self.current_marker_bytes[1] == Some(Markers::DHP) {
+            if self.current_marker_bytes[1] == Some(Markers::DHP) {
+                // Hierarchical mode codes the image as a sequence
+                // of frames, each upsampled (EXP) and refined by
+                // the next (refer to Annex J in the spec). Only a
+                // single frame is decoded here, so the result
+                // would be wrong.
+                return Err(DecodeError::HierarchicalNotSupported);
+            }
+            self.frame.frame_header.build(&self.segment_length, &self.current_marker_bytes[1].unwrap(), &self.segment_data)?;
+            self.options.check_dimensions(&self.frame.frame_header)?;
+        }
+        else if self.current_marker_bytes[1] == Some(Markers::SOS) {
+            let mut scan = Scan::default();
+            scan.scan_header.build(&self.segment_length, &self.segment_data)?;
+            scan.dc_huffman_tables = self.frame.dc_huffman_tables.clone();
+            scan.ac_huffman_tables = self.frame.ac_huffman_tables.clone();
+            scan.arithmetic_tables = self.frame.arithmetic_tables.clone();
+            scan.quantization_tables = self.frame.quantization_tables.clone();
+            self.frame.scans.push(scan);
+        }
+        else if self.current_marker_bytes[1] == Some(Markers::EXP) {
+            let mut exp = ExpandReference::default();
+            exp.build(&self.segment_length, &self.segment_data)?;
+            self.frame.expand_reference = Some(exp);
+        }
+        else if self.current_marker_bytes[1] == Some(Markers::DAC) {
+            // An odd length leaves a table short, which build rejects
+            for table_bytes in self.segment_data.chunks(2) {
+                let mut table = ArithmeticTable::default();
+                table.build(&self.segment_length, table_bytes)?;
+                self.frame.arithmetic_tables.push(table);
+            }
+        }
+        else if self.current_marker_bytes[1] == Some(Markers::DNL) {
+            let mut number_of_lines = NumberOfLines::default();
+            number_of_lines.build(&self.segment_length, &self.segment_data)?;
+            // An SOF height of 0 is filled in by the DNL following the first
+            // scan (refer to B.2.5 in the spec). Some streaming encoders
+            // write a placeholder height instead, which a DNL corrects.
+            let frame = self.frame.frame_header.total_vertical_lines;
+            let lines = number_of_lines.total_lines;
+            if lines != frame {
+                if self.options.strict && (frame != 0 || lines == 0) {
+                    return Err(DecodeError::NumberOfLinesMismatch { frame, lines });
+                }
+                if lines == 0 {
+                    warn!("(Parser::build_segment) warning: DNL gives 0 lines. Keeping the frame header's {}.", frame);
+                }
+                else {
+                    if frame != 0 {
+                        warn!("(Parser::build_segment) warning: DNL gives {} lines but the frame header gives {}. Using the DNL.", lines, frame);
+                    }
+                    self.frame.frame_header.total_vertical_lines = lines;
+                    self.options.check_dimensions(&self.frame.frame_header)?;
+                }
+            }
+            self.frame.lines = Some(number_of_lines);
+        }
+        else if self.current_marker_bytes[1] == Some(Markers::DRI) {
+            let mut restart_interval = RestartInterval::default();
+            restart_interval.build(&self.segment_length, &self.segment_data)?;
+            self.frame.restart_interval = Some(restart_interval);
+        }
+        else if self.current_marker_bytes[1] == Some(Markers::COM) {
+            let mut comment = Comment::default();
+            if self.check_length(comment.build(&self.segment_length, &self.segment_data))? {
+                self.frame.comments.push(comment);
+            }
+        }
+        else if self.current_marker_bytes[1] >= Some(Markers::APP0) 
+        && self.current_marker_bytes[1] <= Some(Markers::APP15) {
+            let mut app_data = ApplicationData::default();
+            if self.check_length(app_data.build(&self.current_marker_bytes[1].unwrap(), &self.segment_length, &self.segment_data))? {
+                app_data.offset = self.marker_offset;
+                if let Some(transform) = app_data.adobe_color_transform() {
+                    self.frame.color_transform = Some(transform);
+                }
+                self.frame.application_data.push(app_data);
+            }
+        }
+        events.push(Event::Segment { marker: self.current_marker_bytes[1].unwrap(), offset: self.marker_offset });
+
+        // Restart the process
+        self.segment_length_bytes = [None;2];
+        self.segment_length = 0;
+        self.segment_data = Vec::new();
+        if self.current_marker_bytes[1] == Some(Markers::SOS) {
+            if self.headers_only {
+                // Stop before any entropy coded data is read
+                self.stage = ReadStage::Finished;
+            }
+            else {
+                // Special case where the segment leads into
+                // image data instead of marker data.
+                self.frame.scans.last_mut().unwrap().data_offset = self.position + 1;
+                self.stage = ReadStage::Scan;
+            }
+        }
+        else {
+            self.stage = ReadStage::Marker;
+        }
+        self.current_marker_bytes = [None;2];
+        return Ok(())
+    }
+
+    // Passes on the result of building a segment, or a table within one. A
+    // length that doesn't match the data is an error in strict mode, while
+    // in lenient mode the segment is discarded and false returned.
+    fn check_length(&self, result: Result<(), DecodeError>) -> Result<bool, DecodeError> {
+        return match result {
+            Ok(()) => Ok(true),
+            Err(DecodeError::SegmentLengthMismatch(marker)) if !self.options.strict => {
+                warn!("(Parser::check_length) warning: segment {:02x?} data does not match its length. Discarding it.", marker);
+                Ok(false)
+            },
+            Err(error) => Err(error)
+        }
+    }
+
+    // The byte after an APPn or COM segment should begin the next marker.
+    // When it doesn't, the segment's length is usually wrong and its data
+    // ran into the segment after it, as happens when metadata is edited
+    // without updating the length. The segment is cut short at the marker
+    // of the segment it ran into and everything from there is read again.
+    // Only these segments are checked, since their contents are free form
+    // while every other segment's length follows from its parameters.
+    //
+    // The overrun is at the end, so the marker is looked for from there.
+    // Searching from the start would find the segments of any JPEG embedded
+    // in the data, such as an Exif thumbnail.
+    fn end_overlong_segment(&mut self, byte: &u8, events: &mut Vec<Event>) -> Result<(), DecodeError> {
+        let mut bytes: Vec<u8> = core::mem::take(&mut self.segment_data);
+        bytes.push(*byte);
+        let marker_idx = (0..bytes.len() - 1).rev().find(|idx| overrun_starts_at(&bytes, idx));
+        let Some(marker_idx) = marker_idx else {
+            // Nothing to fall back on, so skip ahead to the next marker
+            // after the segment as usual
+            bytes.pop();
+            self.segment_data = bytes;
+            self.build_segment(events)?;
+            return self.read_byte(byte, events)
+        };
+        warn!("(ReadStage::SegmentEnd) warning: segment {:02x?} runs {} bytes past the marker at {}. Reading from that marker instead.", self.current_marker_bytes[1].unwrap(), bytes.len() - 1 - marker_idx, self.position + marker_idx + 1 - bytes.len());
+        self.segment_length = marker_idx as u16;
+        self.segment_data = bytes[..marker_idx].to_vec();
+        self.build_segment(events)?;
+        let position = self.position;
+        for (idx, replayed) in bytes.iter().enumerate().skip(marker_idx) {
+            self.position = position + idx + 1 - bytes.len();
+            match self.stage {
+                ReadStage::Finished => break,
+                ReadStage::Trailer => {
+                    self.frame.trailer.extend(&bytes[idx..]);
+                    break;
+                },
+                _ => self.read_byte(replayed, events)?
+            }
+        }
+        self.position = position;
+        return Ok(())
+    }
+
+    fn read_byte(&mut self, byte: &u8, events: &mut Vec<Event>) -> Result<(), DecodeError> {
+        // Every JPEG begins with SOI. Checking it up front gives a clear
+        // error for other kinds of files rather than one from deep in the
+        // state machine.
+        if self.position < 2 && *byte != [0xff, Markers::SOI][self.position] {
+            return Err(DecodeError::NotAJpeg);
+        }
+        // Each file byte is read only once, in order, however the file
+        // was split across calls to feed. As it goes, segment structs are
+        // created to represent the entire file in memory.
+        // The following states are considered as we interate:
+        // - Reading Marker: If either byte in current_marker
+        //   equals 0x00, then we are still reading the marker.
+        //   - Knowing the marker is useful for creating the correct
+        //   struct with the collected segment_data.
+        // - Reading Segment Length: If segment_length equals u16 max
+        //   value, then we are still reading the segment length. 
+        //   Once the segment_length is set to anything else, we move
+        //   onto the next state.
+        //   - Knowing the segment length allows us to detect and
+        //   ignore cases where 0xFF is not meant to indicate a
+        //   marker start.
+        // - Reading Segment Data: If segment_length is not equal to
+        //   segment_data.len(), then we are still reading bytes into 
+        //   segment data. Reading in bytes into an array is useful as
+        //   a consistent input parameter for each segment struct's
+        //   build function.
+        //
+        // Each segment and its purpose within the JPEG is defined in
+        // the spec: https://www.w3.org/Graphics/JPEG/itu-t81.pdf
+        match self.stage {
+            ReadStage::Marker => {
+                if self.current_marker_bytes[0].is_none() {
+                    self.current_marker_bytes[0] = Some(*byte);
+                    self.marker_offset = self.position;
+                } 
+                else if self.current_marker_bytes[1].is_none() {
+                    self.current_marker_bytes[1] = Some(*byte);
+                }
+
+                if self.current_marker_bytes[0] != Some(0xff) {
+                    // Usually padding after a segment or a segment length
+                    // that is slightly off. Skip ahead to the next 0xff.
+                    if self.options.strict {
+                        return Err(DecodeError::InvalidMarker(*byte));
+                    }
+                    if !self.resyncing {
+                        warn!("(ReadStage::Marker) warning: expected a marker, got {:02x?}. Skipping to the next marker.", *byte);
+                        self.resyncing = true;
+                    }
+                    self.current_marker_bytes = [None;2];
+                }
+                else if self.current_marker_bytes[1].is_some() {
+                    self.resyncing = false;
+                    if self.current_marker_bytes[1] > Some(Markers::TEM) 
+                    && self.current_marker_bytes[1] < Some(Markers::SOF0) {
+                        if self.options.strict {
+                            return Err(DecodeError::UnknownMarker(*byte));
+                        }
+                        warn!("(ReadStage::Marker) warning: unknown marker {:02x?}. Skipping to the next marker.", *byte);
+                        self.resyncing = true;
+                        self.current_marker_bytes = [None;2];
+                        return Ok(());
+                    }
+                    match self.current_marker_bytes[1] {
+                        Some(Markers::EOI) => {
+                            // The image is complete. Anything after EOI, such
+                            // as appended thumbnails or the other images of
+                            // an MPO file, is kept aside rather than parsed.
+                            self.found_end_of_image = true;
+                            events.push(Event::EndOfImage);
+                            self.stage = ReadStage::Trailer;
+                        },
+                        Some(marker @ (Markers::TEM | Markers::SOI)) => {
+                            events.push(Event::Segment { marker, offset: self.marker_offset });
+                            self.current_marker_bytes = [None;2];
+                            self.stage = ReadStage::Marker
+                        },
+                        Some(Markers::MRK) => {
+                            // At any point within a JPEG, one 0xff
+                            // may follow another 0xff. The correct
+                            // way to handle this is to treat all
+                            // sequential 0xff values as one.
+                            //
+                            // The first 0xff is kept and the next byte
+                            // is read as the marker code again.
+                            self.current_marker_bytes[1] = None;
+                            self.marker_offset = self.position;
+                        }
+                        Some(marker @ Markers::RST0..=Markers::RST7) => {
+                            // Restart markers separate the entropy coded
+                            // segments of a scan. They carry no length and
+                            // count upwards from RST0, wrapping after RST7.
+                            //
+                            // The encoder pads each segment out to a byte
+                            // boundary, so recording where the next segment
+                            // begins is enough for the decoder to realign.
+                            let current_scan = match self.frame.scans.last_mut() {
+                                Some(scan) => scan,
+                                None => return Err(DecodeError::UnexpectedRestartMarker(marker))
+                            };
+                            let expected = Markers::RST0 + (current_scan.restart_offsets.len() % 8) as u8;
+                            if marker != expected {
+                                if !self.options.recover {
+                                    return Err(DecodeError::RestartMarkerOutOfSequence { expected, found: marker });
+                                }
+                                // The markers in between were lost along with
+                                // their segments. Record those segments as
+                                // empty so the decoder skips their intervals.
+                                warn!("(ReadStage::Marker) warning: Expected {:#04x} but found {:#04x}. Skipping the intervals in between.", expected, marker);
+                                let missing = (marker + 8 - expected) % 8;
+                                for _ in 0..missing {
+                                    current_scan.restart_offsets.push(current_scan.entropy_coded_segments.len());
+                                }
+                            }
+                            current_scan.restart_offsets.push(current_scan.entropy_coded_segments.len());
+                            events.push(Event::Segment { marker, offset: self.marker_offset });
+                            self.current_marker_bytes = [None;2];
+                            self.stage = ReadStage::Scan;
+                        },
+                        Some(Markers::ESC) => {
+                            // Include this data into the image data, the 
+                            // 0xff value is escaped by the following 0x00 
+                            // value. Only the 0xff is kept.
+                            let Some(current_scan) = self.frame.scans.last_mut() else {
+                                // Stuffing only happens in entropy coded data
+                                if self.options.strict {
+                                    return Err(DecodeError::UnknownMarker(Markers::ESC));
+                                }
+                                warn!("(ReadStage::Marker) warning: found 0xff00 outside of a scan. Skipping to the next marker.");
+                                self.resyncing = true;
+                                self.current_marker_bytes = [None;2];
+                                return Ok(());
+                            };
+                            current_scan.stuffed_offsets.push(current_scan.entropy_coded_segments.len());
+                            current_scan.entropy_coded_segments.push(Markers::MRK);
+                            self.current_marker_bytes = [None;2];
+                            self.stage = ReadStage::Scan;
+                        },
+                        _ => self.stage = ReadStage::Length,
+                    }
+                }
+            },
+            ReadStage::Length => {
+                // Length parameter factors its own 2 byte length into
+                // the total which is why we push to the segment data here
+                if self.segment_length_bytes[0].is_none() {
+                    self.segment_length_bytes[0] = Some(*byte);
+                } 
+                else if self.segment_length_bytes[1].is_none() {
+                    self.segment_length_bytes[1] = Some(*byte);
+                    let marker = self.current_marker_bytes[1].unwrap();
+                    self.segment_length = u16::from_be_bytes([
+                        self.segment_length_bytes[0].unwrap(),
+                        self.segment_length_bytes[1].unwrap()
+                    ]).checked_sub(2).ok_or(DecodeError::SegmentLengthMismatch(marker))?;
+                    self.stage = ReadStage::Segment;
+                    if self.segment_length == 0 {
+                        // Nothing follows the length, as in an empty
+                        // comment, so the segment is already complete.
+                        // Every other segment needs some data.
+                        if !matches!(marker, Markers::COM | Markers::APP0..=Markers::APP15 | Markers::DHT | Markers::DQT) {
+                            return Err(DecodeError::SegmentLengthMismatch(marker));
+                        }
+                        self.build_segment(events)?;
+                    }
+                }
+            },
+            ReadStage::Segment => {
+                // DHT and DQT segments are read table by table, starting
+                // with this byte, so that a segment ending partway through
+                // its first table is caught too
+                if self.current_marker_bytes[1] == Some(Markers::DHT) {
+                    self.stage = ReadStage::DHTSegment;
+                    return self.read_byte(byte, events);
+                }
+                if self.current_marker_bytes[1] == Some(Markers::DQT) {
+                    self.stage = ReadStage::DQTSegment;
+                    return self.read_byte(byte, events);
+                }
+                self.segment_data.push(*byte);
+                if self.segment_data.len() == usize::from(self.segment_length) {
+                    if !self.options.strict && matches!(self.current_marker_bytes[1], Some(Markers::COM | Markers::APP0..=Markers::APP15)) {
+                        self.stage = ReadStage::SegmentEnd;
+                    }
+                    else {
+                        self.build_segment(events)?;
+                    }
+                }
+            },
+            ReadStage::SegmentEnd => {
+                if *byte == 0xff {
+                    self.build_segment(events)?;
+                    return self.read_byte(byte, events);
+                }
+                self.end_overlong_segment(byte, events)?;
+            },
+            ReadStage::Scan => {
+                // Image data in a compressed JPEG is not defined by
+                // a length. We must test any 0xFF value we find as it
+                // can be a marker.
+                if *byte == 0xff {
+                    // We may have found a marker
+                    self.current_marker_bytes = [Some(0xff), None];
+                    self.marker_offset = self.position;
+                    self.stage = ReadStage::Marker;
+                }
+                else {
+                    self.frame.scans.last_mut().unwrap().entropy_coded_segments.push(*byte);
+                }
+            },
+            ReadStage::DHTSegment => {
+                self.segment_data.push(*byte);
+            
+                // We check length 17 because that accounts for the
+                // 1 ID byte
+                // 16 huffman size bytes
+                // = 17
+                if self.segment_data.len() == 17 {
+                    // segment_data now contains the table id and
+                    // the total huffman codes per code size.
+                    //
+                    // This is enough to calculate the length
+                    // of this table. (There can be multiple
+                    // huffman tables in one DHT segment)
+                    let total_codes: u16 = self.segment_data[1..].iter().map(|count| u16::from(*count)).sum();
+                    if total_codes > 256 {
+                        // There are only 256 possible symbol values
+                        return Err(DecodeError::InvalidHuffmanTable);
+                    }
+                    self.dht_table_length += total_codes;
+                }
+                // Not an else: a table without codes ends with its counts
+                if self.segment_data.len() == usize::from(self.dht_table_length) {
+                    // Prepare to read the next table
+                    let mut table = HuffmanTable::default();
+                    if self.check_length(table.build(&self.dht_table_length, &self.segment_data))? {
+                        let destination_id = table.destination_id as usize;
+                        if table.class == 0 {
+                            self.frame.dc_huffman_tables[destination_id] = Some(table);
+                        }
+                        else {
+                            self.frame.ac_huffman_tables[destination_id] = Some(table);
+                        }
+                    }
+                    self.segment_data = Vec::new();
+                    self.segment_length = self.segment_length
+                        .checked_sub(self.dht_table_length)
+                        .ok_or(DecodeError::SegmentLengthMismatch(Markers::DHT))?;
+                    self.dht_table_length = 17;
+                }
+                else if self.segment_data.len() == usize::from(self.segment_length) {
+                    // The segment ends partway through a table, which is
+                    // discarded below
+                    self.segment_length = 0;
+                }
+                if self.segment_length == 0 {
+                    if !self.segment_data.is_empty() {
+                        if self.options.strict {
+                            return Err(DecodeError::SegmentLengthMismatch(Markers::DHT));
+                        }
+                        warn!("(DHTSegment) warning: DHT segment completed with unused segment data. Discarding it.");
+                        self.segment_data = Vec::new();
+                        self.dht_table_length = 17;
+                    }
+                    // Restart the process
+                    events.push(Event::Segment { marker: Markers::DHT, offset: self.marker_offset });
+                    self.segment_length_bytes = [None;2];
+                    self.stage = ReadStage::Marker;
+                    self.current_marker_bytes = [None;2];
+                }
+            },
+            ReadStage::DQTSegment => {
+                self.segment_data.push(*byte);
+            
+                // Each table starts with its precision and destination id,
+                // followed by 64 values that are 1 or 2 bytes each
+                // depending on the precision. A segment can hold several
+                // tables of either precision.
+                let dqt_table_length: usize = QuantizationTable::table_length(&self.segment_data[0])?;
+                if self.segment_data.len() == dqt_table_length {
+                    // segment_data now contains the table id and
+                    // all quantization table data.
+                    let mut table = QuantizationTable::default();
+                    if self.check_length(table.build(&(dqt_table_length as u16), &self.segment_data))? {
+                        let destination_id = table.destination_id as usize;
+                        self.frame.quantization_tables[destination_id] = Some(table);
+                    }
+                    self.segment_data = Vec::new();
+                    self.segment_length = self.segment_length
+                        .checked_sub(dqt_table_length as u16)
+                        .ok_or(DecodeError::SegmentLengthMismatch(Markers::DQT))?;
+                }
+                else if self.segment_data.len() == usize::from(self.segment_length) {
+                    // The segment ends partway through a table
+                    if self.options.strict {
+                        return Err(DecodeError::SegmentLengthMismatch(Markers::DQT));
+                    }
+                    warn!("(DQTSegment) warning: DQT segment completed with unused segment data. Discarding it.");
+                    self.segment_data = Vec::new();
+                    self.segment_length = 0;
+                }
+                if self.segment_length == 0 {
+                    // We've read all quantization tables from this segment
+                    // Restart the process
+                    events.push(Event::Segment { marker: Markers::DQT, offset: self.marker_offset });
+                    self.segment_length_bytes = [None;2];
+                    self.stage = ReadStage::Marker;
+                    self.current_marker_bytes = [None;2];
+                }
+            },
+            // feed handles these without reading byte by byte
+            ReadStage::Trailer | ReadStage::Finished => {}
+        }
+
+        return Ok(())
+    }
+}
+
+// Whether the segment a too long length ran into could begin at idx of the
+// bytes read for it. That segment continues past the end of the bytes, so
+// its own length must reach at least that far, unless it's EOI or its length
+// hasn't been read yet.
+fn overrun_starts_at(bytes: &[u8], idx: &usize) -> bool {
+    if bytes[*idx] != Markers::MRK {
+        return false
+    }
+    return match bytes[idx + 1] {
+        Markers::EOI => idx + 2 == bytes.len(),
+        Markers::SOF0..=Markers::SOF15 | Markers::SOS..=Markers::COM => match bytes.get(idx + 2..idx + 4) {
+            Some(length_bytes) => {
+                let length = usize::from(u16::from_be_bytes([length_bytes[0], length_bytes[1]]));
+                length >= 2 && idx + 2 + length >= bytes.len()
+            },
+            None => true
+        },
+        _ => false
+    }
+}
+
+// Parses a whole file at once
+fn read_frame(bytes: &[u8], headers_only: &bool, options: &DecodeOptions) -> Result<Frame, DecodeError> {
+    let mut parser = Parser::new(headers_only, options);
+    parser.feed(bytes)?;
+    return parser.finish()
+}
+
+// Tables defined in the file always take precedence. Only destinations that
+// were referenced but never defined are filled in.
+fn insert_default_tables(frame: &mut Frame) {
+    let scan_tables = frame.scans.iter_mut().map(|scan| &mut scan.quantization_tables);
+    for quantization_tables in core::iter::once(&mut frame.quantization_tables).chain(scan_tables) {
+        for fc in frame.frame_header.components.iter() {
+            let destination_id = fc.quantization_table_selector;
+            if let Some(slot) = quantization_tables.get_mut(destination_id as usize) {
+                if slot.is_none() {
+                    *slot = annex_k::quantization_table(&destination_id);
+                }
+            }
+        }
+    }
+    for scan in frame.scans.iter_mut() {
+        for sc in scan.scan_header.components.iter() {
+            let dc_id = sc.dc_entropy_table_dest;
+            if let Some(slot) = scan.dc_huffman_tables.get_mut(dc_id as usize) {
+                if slot.is_none() {
+                    *slot = annex_k::huffman_table(&0, &dc_id);
+                }
+            }
+            let ac_id = sc.ac_entropy_table_dest;
+            if let Some(slot) = scan.ac_huffman_tables.get_mut(ac_id as usize) {
+                if slot.is_none() {
+                    *slot = annex_k::huffman_table(&1, &ac_id);
+                }
+            }
+        }
+    }
+}
+
+// Decoded pixels laid out top-down, row by row, with the samples of each
+// pixel interleaved in the order given by format.
+#[derive(Default, Debug)]
+pub struct Image {
+    pub width: u16,
+    pub height: u16,
+    pub total_components: u8, // Samples per pixel
+    pub format: PixelFormat,
+    pub pixels: Vec<u8>,
+    // Set when DecodeOptions::allow_truncated let a file that ended partway
+    // through its entropy coded data decode. The missing blocks are gray.
+    pub truncated: bool
+}
+
+// Sample layouts decoded pixels can be packed in. Without a requested
+// format, grayscale images are Gray8 and color images Rgb8.
+#[derive(Default, Clone, Copy, PartialEq, Debug)]
+pub enum PixelFormat {
+    #[default]
+    Rgb8,
+    Rgba8, // Alpha is always opaque
+    Gray8,
+    Bgr8,
+    Bgra8  // As Windows DIBs and GDI expect. Alpha is always opaque.
+}
+
+impl PixelFormat {
+    pub fn samples_per_pixel(&self) -> u8 {
+        return match self {
+            PixelFormat::Gray8 => 1,
+            PixelFormat::Rgb8 | PixelFormat::Bgr8 => 3,
+            PixelFormat::Rgba8 | PixelFormat::Bgra8 => 4
+        }
+    }
+}
+
+// One component's samples at the component's own resolution, row by row.
+// Subsampled chroma planes are smaller than the image.
+#[derive(Default, Debug)]
+pub struct Plane {
+    pub width: u16,
+    pub height: u16,
+    pub samples: Vec<u8>
+}
+
+// Basic facts about an image that can be read without decoding it
+#[derive(Default, Debug)]
+pub struct ImageInfo {
+    pub width: u16,
+    pub height: u16,
+    pub components: u8,
+    pub precision: u8,
+    pub progressive: bool
+}
+
+// Real world files often bend the spec in small ways. By default the
+// decoder is lenient: it warns about such deviations and carries on where it
+// reasonably can. Strict mode turns them into errors.
+#[derive(Clone, Copy, Debug)]
+pub struct DecodeOptions {
+    pub strict: bool,
+    pub color_conversion: ColorConversion,
+    // Fill in the Annex K example tables for any table a frame or scan
+    // refers to without defining it, as Motion JPEG frames expect.
+    pub default_tables: bool,
+    // Layout of the decoded pixels. None keeps the layout the image was
+    // coded in.
+    pub output: Option<PixelFormat>,
+    // Frames larger than these limits are rejected as soon as their SOF
+    // segment is read, before anything is allocated for their pixels. A
+    // small file can otherwise claim dimensions of up to 65535x65535.
+    pub max_pixels: u64,
+    pub max_width: Option<u16>,
+    pub max_height: Option<u16>,
+    // When entropy coded data can't be decoded, skip to the next restart
+    // marker and carry on from there. Damaged restart intervals are left
+    // mid gray. Only files with restart intervals can recover.
+    pub recover: bool,
+    // When Huffman coded data ends before every block has been decoded,
+    // stop there and return what was decoded with Image::truncated set,
+    // rather than failing. The blocks that are missing are left mid gray.
+    // Any block whose bits ran past the end is dropped too, so a file that
+    // only relies on the padding of its last byte loses that block.
+    pub allow_truncated: bool
+}
+
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        DecodeOptions {
+            strict: false,
+            color_conversion: ColorConversion::default(),
+            default_tables: false,
+            output: None,
+            max_pixels: 100_000_000,
+            max_width: None,
+            max_height: None,
+            recover: false,
+            allow_truncated: false
+        }
+    }
+}
+
+impl DecodeOptions {
+    fn check_dimensions(&self, frame_header: &FrameHeader) -> Result<(), DecodeError> {
+        let width = frame_header.total_horizontal_lines;
+        let height = frame_header.total_vertical_lines;
+        if u64::from(width) * u64::from(height) > self.max_pixels
+        || self.max_width.is_some_and(|max| width > max)
+        || self.max_height.is_some_and(|max| height > max) {
+            return Err(DecodeError::DimensionsTooLarge { width, height });
+        }
+        return Ok(())
+    }
+}
+
+// Arithmetic used for YCbCr to RGB conversion. Floating point results can
+// differ slightly between platforms, while the fixed point path (the same
+// one libjpeg uses) is bit exact everywhere.
+#[derive(Default, Clone, Copy, PartialEq, Debug)]
+pub enum ColorConversion {
+    #[default]
+    Float,
+    FixedPoint
+}
+
+pub struct Decoder<'a> {
+    bytes: &'a [u8],
+    options: DecodeOptions
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        return Self::with_options(bytes, DecodeOptions::default())
+    }
+
+    pub fn with_options(bytes: &'a [u8], options: DecodeOptions) -> Self {
+        Self {
+            bytes,
+            options
+        }
+    }
+
+    // In lenient mode:
+    // - Reads past the end of a scan's entropy coded data are filled with
+    //   1-bits, as libjpeg does, so that files with a short final MCU still
+    //   decode.
+    // - Stray bytes between segments are skipped up to the next marker.
+    // - DHT and DQT segments with leftover bytes have them discarded.
+    // - APPn and COM segments whose length runs past the next marker are
+    //   cut short at that marker.
+    // - A missing EOI marker is ignored.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.options.strict = strict;
+        return self
+    }
+
+    pub fn with_output(mut self, format: PixelFormat) -> Self {
+        self.options.output = Some(format);
+        return self
+    }
+
+    // Reads the segments preceding the first scan and stops there, skipping
+    // all entropy coded data. This is much cheaper than a full decode when
+    // only the dimensions are needed.
+    pub fn read_metadata_only(&self) -> Result<ImageInfo, DecodeError> {
+        let frame = read_frame(self.bytes, &true, &self.options)?;
+        let frame_header = &frame.frame_header;
+        if frame_header.marker == 0 {
+            return Err(DecodeError::MissingFrameHeader);
+        }
+        return Ok(ImageInfo {
+            width: frame_header.total_horizontal_lines,
+            height: frame_header.total_vertical_lines,
+            components: frame_header.total_components,
+            precision: frame_header.precision,
+            progressive: frame.is_progressive()
+        })
+    }
+
+    // Parses every segment, scan headers included, without decoding any
+    // entropy coded data
+    pub fn read_segments(&self) -> Result<Frame, DecodeError> {
+        return read_frame(self.bytes, &false, &self.options)
+    }
+
+    pub fn decode(&self) -> Result<Image, DecodeError> {
+        return decode_image(self.bytes, &self.options, None)
+    }
+
+    // Decodes as decode does, calling on_scan_complete with the image
+    // decoded so far each time a scan completes, so that a preview can be
+    // painted while a file with several scans is decoded. Every scan but the
+    // last is decoded once more for it.
+    pub fn decode_with_scan_callback(&self, on_scan_complete: &mut dyn FnMut(&Image)) -> Result<Image, DecodeError> {
+        return decode_image(self.bytes, &self.options, Some(on_scan_complete))
+    }
+
+    // Returns one plane per component in frame header order, which is Y, Cb
+    // and Cr for color files. The samples are neither upsampled nor color
+    // converted.
+    pub fn decode_to_planar_yuv(&self) -> Result<Vec<Plane>, DecodeError> {
+        let frame = read_decodable_frame(self.bytes, &self.options)?;
+        let geometry = McuGeometry::new(&frame.frame_header);
+        let (mcus, _) = decode_samples(&frame, &geometry, &self.options)?;
+        return Ok(planes_from_mcus(&mcus, &frame.frame_header, &geometry))
+    }
+
+    // Returns the plane of the component with the given id, as
+    // decode_to_planar_yuv would. Only that component is dequantized and
+    // transformed, which makes this a cheap way to preview luma or look at
+    // a single chroma channel.
+    pub fn decode_component(&self, component_id: &u8) -> Result<Plane, DecodeError> {
+        let frame = read_decodable_frame(self.bytes, &self.options)?;
+        let fc_idx = frame.frame_header.components
+            .iter()
+            .position(|component| component.id == *component_id)
+            .ok_or(DecodeError::UnknownComponent(*component_id))?;
+        let fc = &frame.frame_header.components[fc_idx];
+        let geometry = McuGeometry::new(&frame.frame_header);
+        let coefficients: Vec<Vec<Vec<[i16; 64]>>> = decode_coefficients(&frame, &geometry, &self.options)?.0
+            .into_iter()
+            .map(|mut mcu| {
+                component_blocks(&mcu, &fc_idx, &geometry.mcu_size)?;
+                return Ok(vec![mcu.swap_remove(fc_idx)])
+            })
+            .collect::<Result<_, DecodeError>>()?;
+        let mcus = reconstruct_samples(&coefficients, core::slice::from_ref(fc), &frame, &geometry)?;
+        return Ok(plane_from_mcus(&mcus, &frame.frame_header, &geometry, fc, &0))
+    }
+
+    // Every component's blocks of samples after the idct and level shift,
+    // before any upsampling or color conversion, as (component id, x, y,
+    // samples). x and y give the block's top left sample within the
+    // component's plane (refer to decode_to_planar_yuv), and the samples are
+    // in row-major order. Components come in frame header order, and each
+    // one's blocks run left to right, top to bottom. Blocks past the edges of
+    // a plane, which only pad out the mcus, are left out.
+    pub fn blocks(&self) -> Result<impl Iterator<Item = (u8, usize, usize, [i16; 64])>, DecodeError> {
+        let frame = read_decodable_frame(self.bytes, &self.options)?;
+        let geometry = McuGeometry::new(&frame.frame_header);
+        let (mcus, _) = decode_samples(&frame, &geometry, &self.options)?;
+        let plane_blocks: Vec<(usize, usize)> = frame.frame_header.components
+            .iter()
+            .map(|fc| {
+                let (width, height) = frame.frame_header.plane_size(fc);
+                return (width.div_ceil(8) as usize, height.div_ceil(8) as usize)
+            })
+            .collect();
+        let blocks = plane_blocks
+            .into_iter()
+            .enumerate()
+            .flat_map(|(fc_idx, (width_blocks, height_blocks))| {
+                (0..height_blocks).flat_map(move |block_y| (0..width_blocks).map(move |block_x| (fc_idx, block_x, block_y)))
+            })
+            .map(move |(fc_idx, block_x, block_y)| {
+                let fc = &frame.frame_header.components[fc_idx];
+                let (mcu_idx, slot) = block_position(&block_x, &block_y, fc, &geometry);
+                return (fc.id, block_x * 8, block_y * 8, mcus[mcu_idx][fc_idx][slot])
+            });
+        return Ok(blocks)
+    }
+
+    // Decodes into a buffer the caller owns, so that it can be reused from
+    // one image to the next, as when decoding Motion JPEG frames. The buffer
+    // must hold at least width * height * samples per pixel bytes. Whether
+    // the data was truncated isn't reported here; use decode for that.
+    pub fn decode_into(&self, out: &mut [u8]) -> Result<(), DecodeError> {
+        let frame = read_decodable_frame(self.bytes, &self.options)?;
+        let format = output_format(&frame, &self.options);
+        let required: usize = frame.frame_header.total_horizontal_lines as usize
+            * frame.frame_header.total_vertical_lines as usize
+            * format.samples_per_pixel() as usize;
+        if out.len() < required {
+            return Err(DecodeError::BufferTooSmall { required, got: out.len() });
+        }
+        decode_frame_into(&frame, &self.options, &format, &mut out[..required])?;
+        return Ok(())
+    }
+}
+
+fn decode_image(bytes: &[u8], options: &DecodeOptions, on_scan_complete: Option<&mut dyn FnMut(&Image)>) -> Result<Image, DecodeError> {
+    let frame = read_decodable_frame(bytes, options)?;
+    return decode_supported_frame(frame, options, on_scan_complete)
+}
+
+// Decodes a frame read by a Parser, as Decoder::decode does for a whole
+// file. options should be the ones the parser was given.
+pub fn decode_frame(frame: Frame, options: &DecodeOptions) -> Result<Image, DecodeError> {
+    let frame = supported_frame(frame, options, &false)?;
+    return decode_supported_frame(frame, options, None)
+}
+
+fn decode_supported_frame(mut frame: Frame, options: &DecodeOptions, mut on_scan_complete: Option<&mut dyn FnMut(&Image)>) -> Result<Image, DecodeError> {
+    let format = output_format(&frame, options);
+    let width = frame.frame_header.total_horizontal_lines;
+    let height = frame.frame_header.total_vertical_lines;
+    let mut pixels: Vec<u8> = vec![0; width as usize * height as usize * format.samples_per_pixel() as usize];
+    if let Some(on_scan_complete) = on_scan_complete.as_mut() {
+        // Decode with only the scans read so far. Blocks of later scans
+        // are left zeroed, which is mid gray.
+        for total_scans in 1..frame.scans.len() {
+            let later_scans = frame.scans.split_off(total_scans);
+            let truncated = decode_frame_into(&frame, options, &format, &mut pixels)?;
+            frame.scans.extend(later_scans);
+            on_scan_complete(&Image {
+                width,
+                height,
+                total_components: format.samples_per_pixel(),
+                format,
+                pixels: pixels.clone(),
+                truncated
+            });
+        }
+    }
+    let truncated = decode_frame_into(&frame, options, &format, &mut pixels)?;
+    let image = Image {
+        width,
+        height,
+        total_components: format.samples_per_pixel(),
+        format,
+        pixels,
+        truncated
+    };
+    if let Some(on_scan_complete) = on_scan_complete {
+        on_scan_complete(&image);
+    }
+    return Ok(image)
+}
+
+// A 1/8 scale preview with one pixel per 8x8 area of the image, made from
+// the DC coefficients alone. A block's DC coefficient is 8 times its mean
+// sample (refer to A.3.3 in the spec), so no idct is needed. Progressive
+// files only need their DC scans, which come first, and their AC scans are
+// skipped. Sequential files are entropy decoded in full, since the DC and
+// AC coefficients of a block are coded together.
+pub fn decode_preview(bytes: &[u8]) -> Result<Image, DecodeError> {
+    let options = DecodeOptions::default();
+    let frame = read_supported_frame(bytes, &options, &true)?;
+    let frame_header = &frame.frame_header;
+    if !matches!(frame_header.total_components, 1..=3) {
+        return Err(DecodeError::UnsupportedComponentCount(frame_header.total_components));
+    }
+    let geometry = McuGeometry::new(frame_header);
+    let (coefficients, truncated) = decode_coefficients(&frame, &geometry, &options)?;
+    let mcus = dequantize(&coefficients, &frame_header.components, &frame, &geometry)?;
+    let width = frame_header.total_horizontal_lines.div_ceil(8);
+    let height = frame_header.total_vertical_lines.div_ceil(8);
+    let format = output_format(&frame, &options);
+    let coded_format = if frame_header.total_components == 3 { PixelFormat::Rgb8 } else { PixelFormat::Gray8 };
+    let is_ycbcr = frame_header.total_components == 3
+        && frame.color_transform != Some(ColorTransform::Unknown);
+    let precision = frame_header.precision;
+    let level_shift: i32 = 1 << (precision - 1);
+    let max_sample: i32 = (1 << precision) - 1;
+    let sample_shift: u8 = precision - 8;
+    let max_horizontal_factor = geometry.max_horizontal_factor as usize;
+    let max_vertical_factor = geometry.max_vertical_factor as usize;
+    let mut pixels: Vec<u8> = vec![0; width as usize * height as usize * format.samples_per_pixel() as usize];
+    let mut out_pixels = pixels.chunks_exact_mut(format.samples_per_pixel() as usize);
+    for y in 0..height as usize {
+        for x in 0..width as usize {
+            let mut samples: [i16; 3] = [0; 3];
+            for (fc_idx, (fc, sample)) in frame_header.components.iter().zip(samples.iter_mut()).enumerate() {
+                // Blocks of subsampled components cover several pixels
+                let h = fc.horizontal_sample_factor as usize;
+                let v = fc.vertical_sample_factor as usize;
+                let block_x = x * h / max_horizontal_factor;
+                let block_y = y * v / max_vertical_factor;
+                let mcu_idx = (block_y / v) * geometry.mcus_per_line + block_x / h;
+                let slot = (block_y % v) * max_horizontal_factor + block_x % h;
+                let dc = mcus[mcu_idx][fc_idx][slot][0];
+                *sample = (math::round(dc as f64 / 8.0) as i32 + level_shift).clamp(0, max_sample) as i16;
+            }
+            if is_ycbcr {
+                samples = ycbcr_to_rgb(&samples, &precision, &options.color_conversion);
+            }
+            let pixel: [u8; 3] = samples.map(|sample| (sample >> sample_shift) as u8);
+            let out_pixel = out_pixels.next().expect("(decode_preview) Output buffer is smaller than the preview");
+            pack_pixel(&pixel, &coded_format, &format, out_pixel);
+        }
+    }
+    return Ok(Image {
+        width,
+        height,
+        total_components: format.samples_per_pixel(),
+        format,
+        pixels,
+        truncated
+    })
+}
+
+// The layout pixels are decoded in: the requested one, or else the layout
+// the image was coded in. 2 component files have no agreed color model.
+// Their first component is output as grayscale, and the second can be read
+// with decode_component.
+fn output_format(frame: &Frame, options: &DecodeOptions) -> PixelFormat {
+    return options.output.unwrap_or(match frame.frame_header.total_components {
+        1 | 2 => PixelFormat::Gray8,
+        _ => PixelFormat::Rgb8
+    })
+}
+
+// Reads a frame and checks that the rest of the pipeline can decode it
+fn read_decodable_frame(bytes: &[u8], options: &DecodeOptions) -> Result<Frame, DecodeError> {
+    return read_supported_frame(bytes, options, &false)
+}
+
+// With dc_only, progressive Huffman frames are also accepted. Only their DC
+// scans can be decoded, which is all decode_preview needs.
+fn read_supported_frame(bytes: &[u8], options: &DecodeOptions, dc_only: &bool) -> Result<Frame, DecodeError> {
+    let frame = read_frame(bytes, &false, options)?;
+    return supported_frame(frame, options, dc_only)
+}
+
+fn supported_frame(mut frame: Frame, options: &DecodeOptions, dc_only: &bool) -> Result<Frame, DecodeError> {
+    if options.default_tables {
+        insert_default_tables(&mut frame);
+    }
+    // Fail early for frames the rest of the pipeline can't decode. Baseline
+    // and extended sequential frames share the same Huffman decoding.
+    // Arithmetic coding is only decoded for sequential frames (SOF9).
+    match frame.coding_process() {
+        None => return Err(DecodeError::MissingFrameHeader),
+        Some(CodingProcess::Baseline | CodingProcess::ExtendedSequential) => {},
+        Some(CodingProcess::Arithmetic) if frame.frame_header.marker == Markers::SOF9 => {},
+        Some(CodingProcess::Progressive) if *dc_only => {},
+        Some(process) => return Err(DecodeError::Unsupported(process))
+    }
+    // DCT frames hold 8 or 12-bit samples (refer to B.2.2 in the spec).
+    // Every later stage derives its ranges from the precision, and would
+    // overflow on anything else.
+    if !matches!(frame.frame_header.precision, 8 | 12) {
+        return Err(DecodeError::UnsupportedPrecision(frame.frame_header.precision));
+    }
+    for scan in frame.scans.iter() {
+        if frame.is_progressive() {
+            scan.scan_header.validate_progressive()?;
+        }
+        else {
+            scan.scan_header.validate_sequential()?;
+        }
+    }
+    return Ok(frame)
+}
+
+// Decodes a frame's pixels into out, which holds exactly width * height
+// pixels in the given format. Returns whether the entropy coded data was
+// cut short (refer to DecodeOptions::allow_truncated).
+fn decode_frame_into(
+    frame: &Frame,
+    options: &DecodeOptions,
+    format: &PixelFormat,
+    out: &mut [u8]
+) -> Result<bool, DecodeError> {
+    let width = frame.frame_header.total_horizontal_lines;
+    let height = frame.frame_header.total_vertical_lines;
+    let geometry = McuGeometry::new(&frame.frame_header);
+    // Other component counts, such as CMYK, have no color conversion yet.
+    // Their planes can still be read with decode_to_planar_yuv.
+    if !matches!(frame.frame_header.total_components, 1..=3) {
+        return Err(DecodeError::UnsupportedComponentCount(frame.frame_header.total_components));
+    }
+    let (mut mcus, truncated) = decode_samples(frame, &geometry, options)?;
+    // A single pass is enough: each component's blocks are stretched by the
+    // full ratio between the max and its own sampling factors, so afterwards
+    // every component covers the whole mcu at full resolution.
+    mcus = upscale(
+        &mcus, 
+        &geometry,
+        &frame.frame_header.components
+    )?;
+    // 3-component files are YCbCr unless an Adobe APP14 segment says the
+    // components were stored untransformed, i.e. as RGB.
+    let is_ycbcr = frame.frame_header.total_components == 3
+        && frame.color_transform != Some(ColorTransform::Unknown);
+    if is_ycbcr {
+        mcus = 
+            ycbcr_to_rgb_mcu(
+                &mcus,
+                &geometry,
+                &frame.frame_header.total_components,
+                &frame.frame_header.precision,
+                &options.color_conversion
+            )?;
+    }
+    pixels_from_mcus(
+        &mcus, 
+        &width, 
+        &height, 
+        &geometry,
+        &frame.frame_header.components,
+        &frame.frame_header.precision,
+        format,
+        out
+    );
+    return Ok(truncated)
+}
+
+// Returns (max vertical, max horizontal) sampling factors, which give the
+// number of blocks an mcu spans in each direction
+fn max_sampling_factors(frame_components: &Vec<FrameComponent>) -> (u8, u8) {
+    let mut max_vertical_factor = 1;
+    let mut max_horizontal_factor = 1;
+    for component in frame_components.iter() {
+        if component.vertical_sample_factor > max_vertical_factor {
+            max_vertical_factor = component.vertical_sample_factor;
+        }
+        if component.horizontal_sample_factor > max_horizontal_factor {
+            max_horizontal_factor = component.horizontal_sample_factor;
+        }
+    }
+    return (max_vertical_factor, max_horizontal_factor)
+}
+
+// Decodes a frame's mcus up to level shifting. Each component's blocks are
+// still at the component's own sampling resolution. Also returns whether the
+// entropy coded data was cut short.
+fn decode_samples(
+    frame: &Frame,
+    geometry: &McuGeometry,
+    options: &DecodeOptions
+) -> Result<(Vec<Vec<Vec<[i16; 64]>>>, bool), DecodeError> {
+    let (mcus, truncated) = decode_coefficients(frame, geometry, options)?;
+    return Ok((reconstruct_samples(&mcus, &frame.frame_header.components, frame, geometry)?, truncated))
+}
+
+// Entropy decodes every scan into mcus of quantized coefficients. Blocks of
+// all components are decoded, since interleaved scans can't be read any
+// other way. Also returns whether the entropy coded data was cut short.
+fn decode_coefficients(
+    frame: &Frame,
+    geometry: &McuGeometry,
+    options: &DecodeOptions
+) -> Result<(Vec<Vec<Vec<[i16; 64]>>>, bool), DecodeError> {
+    let (blocks, truncated) = 
+        decode_huffman_to_blocks(
+            frame, 
+            geometry,
+            &options.strict,
+            &options.recover,
+            &options.allow_truncated
+        )?;
+
+    // mcu structure from outer vector to inner array:
+    // 1. mcu
+    // 2. component
+    // 3. blocks
+    // 4. samples
+    let mcus = partition_blocks_to_mcus(
+            &blocks, 
+            geometry,
+            &frame.frame_header.components
+        )?;
+    return Ok((mcus, truncated))
+}
+
+// Turns coefficients back into samples. components lists the frame
+// components held by each mcu, in order, which may be a subset of the frame's.
+fn reconstruct_samples(
+    mcus: &Vec<Vec<Vec<[i16; 64]>>>,
+    components: &[FrameComponent],
+    frame: &Frame,
+    geometry: &McuGeometry
+) -> Result<Vec<Vec<Vec<[i16; 64]>>>, DecodeError> {
+    let mut samples = dequantize(
+        mcus,
+        components,
+        frame,
+        geometry
+    )?;
+    samples = idct(&samples);
+    samples = level_shift(&samples, &frame.frame_header.precision);
+    return Ok(samples)
+}
+
+// Allows reading data bit by bit (as opposed to byte by byte)
+// Used for huffman decoding. The scan data is borrowed rather than copied
+// since a single scan can be many megabytes.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pub byte_idx: usize,
+    pub bit_idx: usize,
+    strict: bool,
+    // Set once a bit past the end of the data has been asked for
+    pub read_past_end: bool
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8], strict: &bool) -> Self {
+        Self {
+            data,
+            byte_idx: 0,
+            bit_idx: 0,
+            strict: *strict,
+            read_past_end: false
+        }
+    }
+
+    fn is_exhausted(&self) -> bool {
+        return self.byte_idx >= self.data.len()
+    }
+
+    // Whether everything left before the given byte is padding, meaning the
+    // entropy coded segment ending there has been read in full. Encoders
+    // pad the last byte of a segment with 1-bits.
+    fn at_segment_end(&self, end: &usize) -> bool {
+        if self.byte_idx >= *end {
+            return true
+        }
+        if self.byte_idx + 1 < *end || self.bit_idx == 0 {
+            return false
+        }
+        let padding: u8 = 0xff >> self.bit_idx;
+        return self.data[self.byte_idx] & padding == padding
+    }
+
+    fn next_bit(&mut self) -> Option<u8> {
+        if self.is_exhausted() {
+            self.read_past_end = true;
+            // Past the end of the data, the stream is treated as if it
+            // continued with 1-bits unless the reader is strict.
+            if self.strict {
+                return None
+            }
+            return Some(1)
+        }
+        let bit = (self.data[self.byte_idx] >> (7 - self.bit_idx)) & 1;
+        self.bit_idx += 1;
+        if self.bit_idx == 8 {
+            self.bit_idx = 0;
+            self.byte_idx += 1;
+        }
+        return Some(bit)
+    }
+    
+    // RECIEVE function in the spec (F.2.2.4)
+    fn next_bits(&mut self, length: &u8) -> Option<u16> {
+        if *length > 16 {
+            panic!("(next_bits) Length supplied is greater than 16. Overflow error.");
+        }
+        let mut bits: u16 = 0;
+        for _ in 0..usize::from(*length) {
+            let bit = self.next_bit()?;
+            bits = (bits << 1) | u16::from(bit);
+        }
+        return Some(bits)
+    }
+
+    // Reads the next bits without consuming them. Like next_bit, bits past
+    // the end of the data are 1 unless the reader is strict.
+    fn peek_bits(&self, length: &u8) -> Option<u16> {
+        if *length > 16 {
+            panic!("(peek_bits) Length supplied is greater than 16. Overflow error.");
+        }
+        // Any 16 bits fit within the 3 bytes starting at byte_idx
+        let mut window: u32 = 0;
+        for offset in 0..3 {
+            let byte = match self.data.get(self.byte_idx + offset) {
+                Some(byte) => *byte,
+                None if self.strict && offset * 8 < self.bit_idx + usize::from(*length) => return None,
+                None => 0xff
+            };
+            window = (window << 8) | u32::from(byte);
+        }
+        let bits = (window << self.bit_idx) >> (24 - length);
+        return Some((bits & ((1 << length) - 1)) as u16)
+    }
+
+    fn skip_bits(&mut self, length: &u8) {
+        self.bit_idx += usize::from(*length);
+        self.byte_idx += self.bit_idx / 8;
+        self.bit_idx %= 8;
+        if self.byte_idx > self.data.len() || (self.byte_idx == self.data.len() && self.bit_idx > 0) {
+            self.read_past_end = true;
+        }
+    }
+
+    // Move the reader to the 0th bit of the given byte.
+    // This is used to jump to the start of an entropy coded segment.
+    fn seek(&mut self, byte_idx: usize) {
+        self.byte_idx = byte_idx;
+        self.bit_idx = 0;
+    }
+
+    fn align(&mut self) {
+        // Align the reader to the 0th bit of the next byte.
+        // This is used for the beginning of restart intervals.
+        if ((self.byte_idx + 1) >= self.data.len())
+        || self.bit_idx == 0 {
+            return
+        }
+        self.bit_idx = 0;
+        self.byte_idx += 1;
+    }
+
+    // Moves to the segment following restart marker segment_idx, counting
+    // from 0 and not wrapping. The scan reader strips RSTn markers out of
+    // the entropy coded data and records where each one was, so there is no
+    // marker left to consume here, only its offset to check for. Without
+    // one a strict reader fails, and a lenient one aligns to the next byte
+    // since the encoder padded the segment out to one.
+    fn skip_restart_marker(&mut self, restart_offsets: &[usize], segment_idx: &usize) -> Result<(), DecodeError> {
+        match restart_offsets.get(*segment_idx) {
+            Some(offset) => self.seek(*offset),
+            None if self.strict => return Err(DecodeError::MissingRestartMarker(Markers::RST0 + (*segment_idx % 8) as u8)),
+            None => self.align()
+        }
+        return Ok(())
+    }
+}
+
+// DECODE: F.2.2.3, figure F.16 in itu-t81 spec
+fn next_symbol(bit_reader: &mut BitReader, hf: &HuffmanTable) -> Result<u8, DecodeError> {
+    // Short codes are resolved from the peeked bits in one lookup, which
+    // consumes only the code's own bits
+    if let Some(peeked) = bit_reader.peek_bits(&HUFFMAN_LOOKUP_BITS) {
+        if let Some((symbol, length)) = hf.lookup.get(peeked as usize).filter(|(_, length)| *length > 0) {
+            bit_reader.skip_bits(length);
+            return Ok(*symbol)
+        }
+    }
+    return walk_symbol(bit_reader, hf)
+}
+
+// Longer codes are walked bit by bit
+fn walk_symbol(bit_reader: &mut BitReader, hf: &HuffmanTable) -> Result<u8, DecodeError> {
+    let mut code: u16 = bit_reader.next_bit().ok_or(DecodeError::TruncatedStream)?.into();
+    let mut idx = 0;
+    while idx < 16 && (hf.maxcode[idx].is_none() || hf.maxcode[idx].is_some_and(|max| code > max)) {
+        if idx == 15 {
+            // All 16 code lengths have been tried without a match. When the
+            // bits came from padding past the end, the data was cut short.
+            if bit_reader.is_exhausted() {
+                return Err(DecodeError::TruncatedStream);
+            }
+            return Err(DecodeError::InvalidHuffmanCode);
+        }
+        let next_bit: u16 = u16::from(bit_reader.next_bit().ok_or(DecodeError::TruncatedStream)?);
+        code = (code << 1) + next_bit;
+        idx += 1;
+    }
+    let j: usize = hf.valptr[idx] + code as usize - hf.mincode[idx] as usize; 
+    return hf.huffman_values.get(j).copied().ok_or(DecodeError::InvalidHuffmanCode)
+}
+
+// EXTEND procedure (refer to figure F.12 in the spec). Converts the
+// additional bits of a coefficient to its signed value: values in the lower
+// half of a length's range are negative (refer to table F.1 in the spec).
+// Lengths reach 15 for 12-bit DC differences, where 1 << 15 overflows an
+// i16, so the result is an i32.
+fn extend(bits: &u16, length: &u8) -> i32 {
+    if *length == 0 {
+        return 0
+    }
+    let value = *bits as i32;
+    if value < (1 << (length - 1)) {
+        return value - ((1 << length) - 1)
+    }
+    return value
+}
+
+// Decodes a DC difference and adds it to the predictor, which is updated to
+// the new coefficient (refer to F.2.2.1 in the spec)
+fn decode_dc(
+    prev_dc: &mut i16,
+    bit_reader: &mut BitReader,
+    dc: &HuffmanTable,
+    precision: &u8
+) -> Result<i16, DecodeError> {
+    // DC lengths reach 11 for 8-bit samples and 15 for 12-bit samples
+    let max_dc_coeff_length: u8 = precision + 3;
+    let dc_coeff_length = 
+        next_symbol(bit_reader, dc)?;
+    if dc_coeff_length > max_dc_coeff_length {
+        return Err(DecodeError::InvalidCoefficient);
+    }
+    // Coefficient initially is unsigned
+    let dc_coeff_unsigned = 
+        bit_reader
+            .next_bits(&dc_coeff_length)
+            .ok_or(DecodeError::TruncatedStream)?;
+    let dc_coeff: i32 = extend(&dc_coeff_unsigned, &dc_coeff_length);
+    // We add the previous dc value here, refered to as the predictor.
+    *prev_dc = (dc_coeff as i16).wrapping_add(*prev_dc);
+    return Ok(*prev_dc)
+}
+
+fn decode_block(
+    prev_dc: &mut i16,
+    bit_reader: &mut BitReader,
+    dc: &HuffmanTable,
+    ac: &HuffmanTable,
+    precision: &u8
+) -> Result<[i16; 64], DecodeError> {
+    let mut data_block: [i16; 64] = [0; 64];
+    // Coefficient lengths grow with sample precision (refer to F.1.2.1.2
+    // and F.1.2.2.1 in the spec). For 8-bit samples AC lengths reach 10,
+    // and 14 for 12-bit samples.
+    let max_ac_coeff_length: u8 = precision + 2;
+    // Coefficients are stored in the zigzag order they are decoded in. They
+    // are only reordered after dequantization.
+    data_block[0] = decode_dc(prev_dc, bit_reader, dc, precision)?;
+    let mut ac_counter: usize = 1;
+    while ac_counter < 64 {
+        let ac_symbol = 
+            next_symbol(bit_reader, ac)?;
+        if ac_symbol == 0x00 {
+            // 0x00 is a special symbol which tells us to fill the
+            // rest of the mcu with zeros
+            //
+            // We've already initialized mcu with all zeros,
+            // so we stop setting any more non-zero values
+            // by returning the mcu.
+            return Ok(data_block)
+        }
+        let mut preceeding_zeros: usize = usize::from(ac_symbol >> 4);
+        // Positions the zeros (and the coefficient following them, if any)
+        // take up. ZRL stands for 16 zeros with no coefficient after them,
+        // so a ZRL may end on the last position, index 63.
+        let mut run_length: usize = preceeding_zeros + 1;
+        if ac_symbol == 0xf0 {
+            preceeding_zeros = 16;
+            run_length = 16;
+        }
+        if ac_counter + run_length > 64 {
+            // Total preceeding zeros exceeds bounds of current data block
+            return Err(DecodeError::InvalidCoefficient);
+        }
+        // We have already initialized the mcu array with zeros, so we
+        // "add" zeros to the mcu by simply adding to the ac_counter.
+        ac_counter += preceeding_zeros;
+        let ac_coeff_length: u8 = ac_symbol & 0x0f;
+        if ac_coeff_length > max_ac_coeff_length {
+            return Err(DecodeError::InvalidCoefficient);
+        }
+        else if ac_coeff_length > 0 {
+            let ac_coeff_unsigned = 
+                bit_reader
+                    .next_bits(&ac_coeff_length) 
+                    .ok_or(DecodeError::TruncatedStream)?;
+            // At most 14 bits long, so the value fits an i16
+            data_block[ac_counter] = extend(&ac_coeff_unsigned, &ac_coeff_length) as i16;
+            ac_counter += 1;
+        }
+    }
+    return Ok(data_block)
+}
+
+// How a frame's blocks are grouped into mcus (refer to A.2 in the spec).
+// Every stage of the pipeline works from the same geometry, computed once
+// per frame.
+#[derive(Clone, Debug, PartialEq)]
+struct McuGeometry {
+    pub max_vertical_factor: u8,
+    pub max_horizontal_factor: u8,
+    // Blocks covering the image, before padding to whole mcus
+    pub width_blocks: u16,
+    pub height_blocks: u16,
+    pub width_blocks_padding: u16,
+    pub height_blocks_padding: u16,
+    pub mcus_per_line: usize,
+    pub total_mcus: usize,
+    // Each component's h * v blocks in an interleaved mcu, the index of
+    // its first block there, and the total over all components
+    pub blocks_per_component: Vec<usize>,
+    pub component_offsets: Vec<usize>,
+    pub blocks_per_mcu: usize,
+    // Slots each component has in a partitioned mcu, one per block of a
+    // component sampled at the max factors
+    pub mcu_size: usize
+}
+
+impl McuGeometry {
+    fn new(frame_header: &FrameHeader) -> McuGeometry {
+        let (max_vertical_factor, max_horizontal_factor) = max_sampling_factors(&frame_header.components);
+        let width_blocks = frame_header.total_horizontal_lines.div_ceil(8);
+        let height_blocks = frame_header.total_vertical_lines.div_ceil(8);
+        let width_blocks_padding = mcu_padding(&width_blocks, &max_horizontal_factor);
+        let height_blocks_padding = mcu_padding(&height_blocks, &max_vertical_factor);
+        let mcus_per_line = (width_blocks + width_blocks_padding) as usize / max_horizontal_factor as usize;
+        let mcu_lines = (height_blocks + height_blocks_padding) as usize / max_vertical_factor as usize;
+        let mut blocks_per_component: Vec<usize> = Vec::with_capacity(frame_header.components.len());
+        let mut component_offsets: Vec<usize> = Vec::with_capacity(frame_header.components.len());
+        let mut blocks_per_mcu: usize = 0;
+        for component in frame_header.components.iter() {
+            let blocks = component.horizontal_sample_factor as usize * component.vertical_sample_factor as usize;
+            blocks_per_component.push(blocks);
+            component_offsets.push(blocks_per_mcu);
+            blocks_per_mcu += blocks;
+        }
+        return McuGeometry {
+            max_vertical_factor,
+            max_horizontal_factor,
+            width_blocks,
+            height_blocks,
+            width_blocks_padding,
+            height_blocks_padding,
+            mcus_per_line,
+            total_mcus: mcus_per_line * mcu_lines,
+            blocks_per_component,
+            component_offsets,
+            blocks_per_mcu,
+            mcu_size: max_vertical_factor as usize * max_horizontal_factor as usize
+        }
+    }
+}
+
+// Number of blocks needed to round total_blocks up to a whole number of mcus.
+// Each mcu spans max_factor blocks along the dimension being padded.
+fn mcu_padding(total_blocks: &u16, max_factor: &u8) -> u16 {
+    let max_factor = *max_factor as u16;
+    return (max_factor - total_blocks % max_factor) % max_factor
+}
+
+fn decode_huffman_to_blocks(
+    frame: &Frame, 
+    geometry: &McuGeometry,
+    strict: &bool,
+    recover: &bool,
+    allow_truncated: &bool
+) -> Result<(Vec<[i16; 64]>, bool), DecodeError> {
+    // The dimensions of a non-interleaved mcu is 8x8 (the same as a data unit)
+    // An interleaved mcu can contain one or more data units per component.
+    let blocks_per_mcu = geometry.blocks_per_mcu;
+    // Blocks are stored in interleaved order regardless of how the scans
+    // ordered them: mcu by mcu, then component by component, then each
+    // component's blocks left-to-right, top-to-bottom.
+    let mut blocks: Vec<[i16; 64]> = vec![[0; 64]; geometry.total_mcus * blocks_per_mcu];
+    let mut truncated: bool = false;
+    // Only the DC scans of progressive frames are decoded, for previews.
+    // AC scans are skipped, leaving every other coefficient zero.
+    let progressive: bool = frame.is_progressive();
+    for (scan_idx, scan) in frame.scans.iter().enumerate() {
+        if progressive && scan.scan_header.spectral_selection_start != 0 {
+            continue;
+        }
+        // The first DC scan codes each coefficient shifted right by Al.
+        // Refinement scans then add one lower bit at a time, uncoded
+        // (refer to G.1.2.1 in the spec).
+        let dc_refinement: bool = progressive && scan.scan_header.successive_approximation_hi != 0;
+        let point_transform: u8 = scan.scan_header.successive_approximation_lo;
+        let mut prev_dc: Vec<i16> = vec![0; frame.frame_header.total_components as usize];
+        let mut bit_reader = BitReader::new(&scan.entropy_coded_segments, strict);
+        // Arithmetic coded scans are read by their own decoder in place of
+        // the bit reader. It needs to know where each segment ends.
+        let segment_end = |segment_idx: usize| -> usize {
+            return *scan.restart_offsets.get(segment_idx).unwrap_or(&scan.entropy_coded_segments.len())
+        };
+        let mut arithmetic_decoder: Option<arithmetic::ArithmeticDecoder> = (frame.coding_process() == Some(CodingProcess::Arithmetic)).then(|| {
+            arithmetic::ArithmeticDecoder::new(&scan.entropy_coded_segments, &segment_end(0), &scan.arithmetic_tables, &frame.frame_header.components.len())
+        });
+        let mut restart_interval: usize = frame.restart_interval.as_ref().map_or(0, |ri| ri.interval as usize);
+        // Set once decoding fails within a restart interval in recover
+        // mode. The rest of that interval's blocks are left zeroed.
+        let mut damaged: bool = false;
+        // A scan with one component is non-interleaved (refer to A.2.2 in
+        // the spec). Its mcu is a single data unit and data units are
+        // ordered left-to-right, top-to-bottom across the component's own
+        // dimensions rather than the dimensions of the interleaved mcus.
+        let interleaved: bool = scan.scan_header.total_components > 1;
+        let mut component_width_blocks: usize = 0;
+        let mut total_units: usize = geometry.total_mcus;
+        if !interleaved {
+            let fc = &frame.frame_header.components[frame.frame_header.component_index(&scan.scan_header.components[0].id)?];
+            let (component_width, component_height) = frame.frame_header.plane_size(fc);
+            component_width_blocks = component_width.div_ceil(8) as usize;
+            total_units = component_width_blocks * component_height.div_ceil(8) as usize;
+        }
+        let mut unit_idx: usize = 0;
+        'units: while unit_idx < total_units {
+            // Restart markers without a DRI segment, as in some recovered
+            // files, still end their segments. The interval is however many
+            // units the first segment held. Arithmetic decoders read ahead
+            // of the data they've decoded, so this only works for Huffman.
+            if restart_interval == 0 && unit_idx > 0 && arithmetic_decoder.is_none() {
+                if let Some(end) = scan.restart_offsets.first() {
+                    if bit_reader.at_segment_end(end) {
+                        warn!("(decode_huffman_to_blocks) warning: restart markers without a DRI segment. Using an interval of {}.", unit_idx);
+                        restart_interval = unit_idx;
+                    }
+                }
+            }
+            let restart: bool = unit_idx > 0 && restart_interval > 0 && unit_idx.is_multiple_of(restart_interval);
+            if restart {
+                // A new entropy coded segment begins here. Every predictor
+                // is reset and the reader jumps to the byte following the
+                // RSTn marker, skipping any padding bits left in the last one.
+                for dc in prev_dc.iter_mut() {
+                    *dc = 0;
+                }
+                let segment_idx = unit_idx / restart_interval - 1;
+                bit_reader.skip_restart_marker(&scan.restart_offsets, &segment_idx)
+                    .map_err(|error| error.at(scan.file_offset(&bit_reader.byte_idx), Some(scan_idx)))?;
+                if let Some(decoder) = arithmetic_decoder.as_mut() {
+                    let start = scan.restart_offsets.get(segment_idx).copied().unwrap_or(decoder.byte_idx);
+                    decoder.restart(&start, &segment_end(segment_idx + 1));
+                }
+                // Segments whose markers went missing were recorded as empty
+                damaged = *recover && matches!(
+                    (scan.restart_offsets.get(segment_idx), scan.restart_offsets.get(segment_idx + 1)),
+                    (Some(start), Some(end)) if start == end
+                );
+            }
+            if damaged {
+                unit_idx += 1;
+                continue;
+            }
+            for sc in scan.scan_header.components.iter() {
+                let cid: usize = frame.frame_header.component_index(&sc.id)?;
+                let fc = &frame.frame_header.components[cid];
+                let h: usize = fc.horizontal_sample_factor as usize;
+                let v: usize = fc.vertical_sample_factor as usize;
+                let units: usize = if interleaved { geometry.blocks_per_component[cid] } else { 1 };
+                if damaged {
+                    break;
+                }
+                for block_idx in 0..units {
+                    let (mcu_idx, mcu_block_idx) = if interleaved {
+                        (unit_idx, block_idx)
+                    }
+                    else {
+                        // Find the interleaved mcu that covers this data unit
+                        let block_x = unit_idx % component_width_blocks;
+                        let block_y = unit_idx / component_width_blocks;
+                        let mcu_idx = (block_y / v) * geometry.mcus_per_line + block_x / h;
+                        (mcu_idx, (block_y % v) * h + block_x % h)
+                    };
+                    let block_pos: usize = mcu_idx * blocks_per_mcu + geometry.component_offsets[cid] + mcu_block_idx;
+                    // Arithmetic coding has no tables to look up, only
+                    // conditioning, which has defaults
+                    let decoded = match arithmetic_decoder.as_mut() {
+                        Some(decoder) => decoder.decode_block(&mut prev_dc[cid], &cid, &sc.dc_entropy_table_dest, &sc.ac_entropy_table_dest),
+                        None if dc_refinement => {
+                            let mut block = blocks[block_pos];
+                            bit_reader.next_bit().ok_or(DecodeError::TruncatedStream).map(|bit| {
+                                block[0] |= i16::from(bit) << point_transform;
+                                return block
+                            })
+                        },
+                        None if progressive => {
+                            let dc = scan.dc_huffman_tables.get(sc.dc_entropy_table_dest as usize).and_then(Option::as_ref)
+                                .ok_or(DecodeError::MissingHuffmanTable { class: 0, destination_id: sc.dc_entropy_table_dest })?;
+                            let mut block = blocks[block_pos];
+                            decode_dc(&mut prev_dc[cid], &mut bit_reader, dc, &frame.frame_header.precision).map(|coefficient| {
+                                block[0] = coefficient << point_transform;
+                                return block
+                            })
+                        },
+                        None => {
+                            let dc = scan.dc_huffman_tables.get(sc.dc_entropy_table_dest as usize).and_then(Option::as_ref)
+                                .ok_or(DecodeError::MissingHuffmanTable { class: 0, destination_id: sc.dc_entropy_table_dest })?;
+                            let ac = scan.ac_huffman_tables.get(sc.ac_entropy_table_dest as usize).and_then(Option::as_ref)
+                                .ok_or(DecodeError::MissingHuffmanTable { class: 1, destination_id: sc.ac_entropy_table_dest })?;
+                            decode_block(&mut prev_dc[cid], &mut bit_reader, dc, ac, &frame.frame_header.precision)
+                        }
+                    };
+                    if *allow_truncated && bit_reader.read_past_end {
+                        // The data ran out partway through this block, so
+                        // it and everything after it are left zeroed
+                        truncated = true;
+                        break 'units;
+                    }
+                    let block = match decoded {
+                        Ok(block) => block,
+                        Err(error) if *recover && restart_interval > 0 => {
+                            warn!("(decode_huffman_to_blocks) warning: {} in restart interval {}. Skipping to the next one.", error, unit_idx / restart_interval);
+                            damaged = true;
+                            break;
+                        },
+                        Err(error) => {
+                            let byte_idx = arithmetic_decoder.as_ref().map_or(bit_reader.byte_idx, |decoder| decoder.byte_idx);
+                            return Err(error.at(scan.file_offset(&byte_idx), Some(scan_idx)))
+                        }
+                    };
+                    blocks[block_pos] = block;
+                }
+            }
+            unit_idx += 1;
+        }
+    }
+    return Ok((blocks, truncated))
+}
+
+fn partition_blocks_to_mcus(
+    blocks: &Vec<[i16; 64]>, 
+    geometry: &McuGeometry,
+    frame_components: &Vec<FrameComponent>
+) -> Result<Vec<Vec<Vec<[i16; 64]>>>, DecodeError> {
+    let mut mcus: Vec<Vec<Vec<[i16; 64]>>> = Vec::new();
+    let mut blocks_idx = 0;
+    let max_horizontal_factor = geometry.max_horizontal_factor;
+    let total_mcus = geometry.total_mcus;
+    let expected: usize = total_mcus * geometry.blocks_per_mcu;
+    if blocks.len() < expected {
+        // The scans decoded fewer blocks than the frame geometry requires
+        return Err(DecodeError::BlockCountMismatch { expected, got: blocks.len() });
+    }
+    let mut mcu_idx = 0;
+    while mcu_idx < total_mcus {
+        let mut mcu: Vec<Vec<[i16; 64]>> = Vec::new();
+        for fc in frame_components.iter() {
+            let total_component_blocks = fc.horizontal_sample_factor * fc.vertical_sample_factor;
+            if total_component_blocks == 0 {
+                continue;
+            }
+            // Add placeholder blocks such that all components
+            // contain the same number of blocks
+            let mut component: Vec<[i16; 64]> = vec![[0; 64]; geometry.mcu_size];
+            for cb_y in 0..fc.vertical_sample_factor {
+                for cb_x in 0..fc.horizontal_sample_factor {
+                    // This indexing places blocks into the correct spot within the component
+                    let i: usize = (cb_y * max_horizontal_factor + cb_x) as usize;
+                    component[i] = blocks[blocks_idx];
+                    blocks_idx += 1;
+                }
+            }
+            mcu.push(component);
+        }
+        mcus.push(mcu);
+        mcu_idx += 1;
+    }
+    return Ok(mcus);
+}
+
+// Runs one stage of the pipeline over every mcu, collecting the results in
+// order. Past entropy decoding each mcu is independent of the others, so
+// with the rayon feature the mcus are spread over rayon's thread pool.
+// Stages that can fail collect into a Result, stopping at an error.
+#[cfg(feature = "rayon")]
+fn map_mcus<'a, T, C, F>(mcus: &'a [Vec<Vec<[i16; 64]>>], stage: F) -> C
+where
+    T: Send,
+    C: rayon::iter::FromParallelIterator<T>,
+    F: Fn(&'a Vec<Vec<[i16; 64]>>) -> T + Sync + Send
+{
+    use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+    return mcus.par_iter().map(stage).collect()
+}
+
+#[cfg(not(feature = "rayon"))]
+fn map_mcus<'a, T, C, F>(mcus: &'a [Vec<Vec<[i16; 64]>>], stage: F) -> C
+where
+    C: FromIterator<T>,
+    F: Fn(&'a Vec<Vec<[i16; 64]>>) -> T
+{
+    return mcus.iter().map(stage).collect()
+}
+
+// The block and the table's elements are both in zigzag order, so they line
+// up index for index
+fn dequantize_block(block: &[i16; 64], qt: &QuantizationTable) -> [i16; 64] {
+    let mut dequantized_block: [i16; 64] = [0; 64];
+    for idx in 0..64 {
+        // 16-bit quantizers can push the product past i16
+        let product: i32 = block[idx] as i32 * qt.elements[idx] as i32;
+        dequantized_block[idx] = product.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+    }
+    return dequantized_block
+}
+
+fn dequantize(
+    mcus: &Vec<Vec<Vec<[i16; 64]>>>,
+    frame_components: &[FrameComponent],
+    frame: &Frame,
+    geometry: &McuGeometry
+) -> Result<Vec<Vec<Vec<[i16; 64]>>>, DecodeError> {
+    let mcu_size: usize = geometry.mcu_size;
+    return map_mcus(mcus, |mcu| {
+        let mut dequantized_mcu: Vec<Vec<[i16; 64]>> = Vec::new();
+        for (fc_idx, fc) in frame_components.iter().enumerate() {
+            let mut dequantized_component: Vec<[i16; 64]> = Vec::new();
+            let qt: &QuantizationTable = frame.quantization_table(fc)?;
+            let component = component_blocks(mcu, &fc_idx, &mcu_size)?;
+            for block in component.iter().take(mcu_size) {
+                dequantized_component.push(dequantize_block(block, qt));
+            }
+            dequantized_mcu.push(dequantized_component);
+        }
+        return Ok(dequantized_mcu)
+    })
+}
+
+// Looks up a frame component's blocks within an mcu, checking that the mcu
+// actually holds the blocks later stages will index into. Components are
+// stored in frame header order, whatever their ids are.
+fn component_blocks<'a>(
+    mcu: &'a Vec<Vec<[i16; 64]>>,
+    component_idx: &usize,
+    expected: &usize
+) -> Result<&'a Vec<[i16; 64]>, DecodeError> {
+    let component = mcu
+        .get(*component_idx)
+        .ok_or(DecodeError::BlockCountMismatch { expected: *expected, got: 0 })?;
+    if component.len() < *expected {
+        return Err(DecodeError::BlockCountMismatch { expected: *expected, got: component.len() });
+    }
+    return Ok(component)
+}
+
+// Inverse Discrete Cosine Transform (aka DCTIII)
+fn idct(mcus: &Vec<Vec<Vec<[i16; 64]>>>) -> Vec<Vec<Vec<[i16; 64]>>> {
+    let basis = idct::Basis::new();
+    let idct_block = idct::select();
+    return map_mcus(mcus, |mcu| {
+        let mut shifted_mcu: Vec<Vec<[i16; 64]>> = Vec::new();
+        for component in mcu.iter() {
+            let mut shifted_component: Vec<[i16; 64]> = Vec::new();
+            for block in component.iter() {
+                shifted_component.push(idct_block(&zigzag::natural_order(block), &basis));
+            }
+            shifted_mcu.push(shifted_component);
+        }
+        return shifted_mcu
+    })
+}
+
+// Samples are centered on zero after the IDCT. The level shift moves them
+// back into the unsigned range 0..=max_sample (refer to A.3.1 in the spec).
+// This applies to every component, whether or not color conversion follows.
+fn level_shift(mcus: &Vec<Vec<Vec<[i16; 64]>>>, precision: &u8) -> Vec<Vec<Vec<[i16; 64]>>> {
+    let level_shift: i16 = 1 << (precision - 1);
+    let max_sample: i16 = (1 << precision) - 1;
+    return map_mcus(mcus, |mcu| {
+        let mut shifted_mcu: Vec<Vec<[i16; 64]>> = mcu.clone();
+        for component in shifted_mcu.iter_mut() {
+            for block in component.iter_mut() {
+                for sample in block.iter_mut() {
+                    // The idct saturates rather than wraps, so samples at
+                    // the ends of the i16 range must not wrap here either
+                    *sample = sample.saturating_add(level_shift).clamp(0, max_sample);
+                }
+            }
+        }
+        return shifted_mcu
+    })
+}
+
+// Each sample in the block is converted into a subblock with dimensions of horizontal_scaling_factor by vertical_scaling_factor. 
+// These subblocks are then spread across a set of new blocks. The total number of new blocks is govered by
+// horizontal_scaling_factor * vertical_scaling_factor.
+//
+// The result is an upscaled version of a block based on scaling factors.
+fn upscale_block(
+    block: &[i16; 64], 
+    horizontal_scaling_factor: usize, 
+    vertical_scaling_factor: usize
+) -> Vec<[i16; 64]> {
+    let mut upscaled: Vec<[i16; 64]> = Vec::new();
+    if horizontal_scaling_factor * vertical_scaling_factor <= 1 {
+        // No work needs to be done when both scaling factors equal 1
+        upscaled.push(*block);
+        return upscaled
+    }
+    for _ in 0..vertical_scaling_factor * horizontal_scaling_factor {
+        upscaled.push([0; 64]);
+    }
+    for upscaled_y in 0..vertical_scaling_factor {
+        // The upscaled index refers to a sample's index across all blocks in the upscaled vector
+        let upscaled_y_idx = upscaled_y * 8;
+        for upscaled_x in 0..horizontal_scaling_factor {
+            let upscaled_x_idx = upscaled_x * 8;
+            for y in 0..8 {
+                for x in 0..8 {
+                    let upscaled_block_idx: usize = upscaled_y * horizontal_scaling_factor + upscaled_x;
+                    let upscaled_sample_idx: usize = y * 8 + x;
+                    let sample_y = (y + upscaled_y_idx) / vertical_scaling_factor;
+                    let sample_x = (x + upscaled_x_idx) / horizontal_scaling_factor;
+                    let sample_idx = sample_y * 8 + sample_x;
+                    upscaled[upscaled_block_idx][upscaled_sample_idx] = block[sample_idx];
+                }
+            }
+        }
+    }
+    return upscaled
+}
+
+fn upscale(
+    mcus: &Vec<Vec<Vec<[i16; 64]>>>, 
+    geometry: &McuGeometry,
+    frame_components: &Vec<FrameComponent>
+) -> Result<Vec<Vec<Vec<[i16; 64]>>>, DecodeError> {
+    let max_horizontal_factor = &geometry.max_horizontal_factor;
+    let max_vertical_factor = &geometry.max_vertical_factor;
+    return map_mcus(mcus, |mcu| {
+        let mut upscaled_mcu: Vec<Vec<[i16; 64]>> = Vec::new();
+        for (fc_idx, fc) in frame_components.iter().enumerate() {
+            let total_component_blocks = fc.horizontal_sample_factor * fc.vertical_sample_factor;
+            if total_component_blocks == 0 {
+                continue;
+            }
+            let mut upscaled_component: Vec<[i16; 64]> = Vec::new();
+            for _ in 0..geometry.mcu_size {
+                upscaled_component.push([0; 64]);
+            }
+            // partition_blocks_to_mcus lays a component's blocks out on the
+            // mcu's grid of max_horizontal_factor by max_vertical_factor
+            // slots, filling the top left h by v slots. Each block is
+            // stretched to cover (max_h / h) by (max_v / v) slots, so block
+            // (cb_x, cb_y) ends up starting at slot (cb_x * x_scale, cb_y * y_scale).
+            // For 4:2:2 (max 2x1, chroma 1x1) the single chroma block in
+            // slot 0 is stretched horizontally into slots 0 and 1.
+            let x_scale = (*max_horizontal_factor / fc.horizontal_sample_factor) as usize; 
+            let y_scale = (*max_vertical_factor / fc.vertical_sample_factor) as usize;
+            let mcu_width = *max_horizontal_factor as usize;
+            let component = component_blocks(mcu, &fc_idx, &(total_component_blocks as usize))?;
+            for cb_y in 0..fc.vertical_sample_factor as usize {
+                for cb_x in 0..fc.horizontal_sample_factor as usize {
+                    let upscaled_blocks: Vec<[i16; 64]> = 
+                        upscale_block(
+                            &component[cb_y * mcu_width + cb_x], 
+                            x_scale,
+                            y_scale
+                        );
+                    // upscale_block returns its blocks in row-major order
+                    for (ub_idx, ub) in upscaled_blocks.iter().enumerate() {
+                        let uc_y = cb_y * y_scale + ub_idx / x_scale;
+                        let uc_x = cb_x * x_scale + ub_idx % x_scale;
+                        upscaled_component[uc_y * mcu_width + uc_x] = *ub;
+                    }
+                }
+            }
+            upscaled_mcu.push(upscaled_component);
+        }
+        return Ok(upscaled_mcu)
+    })
+}
+
+// Converts one pixel from YCbCr to RGB (refer to the JFIF specification),
+// clamping each sample to the range of the precision
+fn ycbcr_to_rgb(ycbcr: &[i16; 3], precision: &u8, color_conversion: &ColorConversion) -> [i16; 3] {
+    // Samples have already been level shifted, so the chroma components are
+    // centered on level_shift rather than zero.
+    let level_shift: f32 = (1 << (precision - 1)) as f32;
+    // JFIF coefficients scaled by 2^16 for the fixed point path
+    const FIXED_SHIFT: i32 = 16;
+    const FIXED_HALF: i32 = 1 << (FIXED_SHIFT - 1);
+    const CR_TO_R: i32 = 91881;  // 1.402
+    const CB_TO_G: i32 = 22554;  // 0.344136
+    const CR_TO_G: i32 = 46802;  // 0.714136
+    const CB_TO_B: i32 = 116130; // 1.772
+    let max_sample: i16 = (1 << precision) - 1;
+    let rgb: [i16; 3] = if *color_conversion == ColorConversion::FixedPoint {
+        let y: i32 =  ycbcr[0] as i32;
+        let cb: i32 = ycbcr[1] as i32 - level_shift as i32;
+        let cr: i32 = ycbcr[2] as i32 - level_shift as i32;
+        [
+            (y + ((CR_TO_R * cr + FIXED_HALF) >> FIXED_SHIFT)) as i16,
+            (y + ((-CB_TO_G * cb - CR_TO_G * cr + FIXED_HALF) >> FIXED_SHIFT)) as i16,
+            (y + ((CB_TO_B * cb + FIXED_HALF) >> FIXED_SHIFT)) as i16
+        ]
+    }
+    else {
+        let y: f32 =  ycbcr[0] as f32;
+        let cb: f32 = ycbcr[1] as f32 - level_shift;
+        let cr: f32 = ycbcr[2] as f32 - level_shift;
+        [
+            math::roundf(y + 1.402 * cr) as i16,
+            math::roundf(y - (0.344136 * cb) - (0.714136 * cr)) as i16,
+            math::roundf(y + 1.772 * cb) as i16
+        ]
+    };
+    return rgb.map(|sample| sample.clamp(0, max_sample))
+}
+
+fn ycbcr_to_rgb_mcu(
+    mcus: &Vec<Vec<Vec<[i16; 64]>>>, 
+    geometry: &McuGeometry,
+    total_components: &u8, 
+    precision: &u8,
+    color_conversion: &ColorConversion
+) -> Result<Vec<Vec<Vec<[i16; 64]>>>, DecodeError> {
+    let mcu_size = geometry.mcu_size;
+    if *total_components == 1 {
+        return Ok(mcus.clone());
+    }
+    else if *total_components != 3 {
+        return Err(DecodeError::UnsupportedComponentCount(*total_components));
+    }
+    return map_mcus(mcus, |mcu| {
+        // Every component must cover the whole mcu by now, which a
+        // malformed frame can break upstream
+        let y = component_blocks(mcu, &0, &mcu_size)?;
+        let cb = component_blocks(mcu, &1, &mcu_size)?;
+        let cr = component_blocks(mcu, &2, &mcu_size)?;
+        // Allocate memory for array access on conversion
+        let mut rgb_mcu: Vec<Vec<[i16; 64]>> = vec![vec![[0; 64]; mcu_size]; 3];
+        for block_idx in 0..mcu_size {
+            for pixel_idx in 0..64 {
+                let ycbcr: [i16; 3] = [
+                    y[block_idx][pixel_idx],
+                    cb[block_idx][pixel_idx],
+                    cr[block_idx][pixel_idx]
+                ];
+                let rgb = ycbcr_to_rgb(&ycbcr, precision, color_conversion);
+                for (c, sample) in rgb.iter().enumerate() {
+                    rgb_mcu[c][block_idx][pixel_idx] = *sample;
+                }
+            }
+        }
+        return Ok(rgb_mcu)
+    })
+}
+
+fn pixels_from_mcus(
+    mcus: &Vec<Vec<Vec<[i16; 64]>>>, 
+    width: &u16,
+    height: &u16,
+    geometry: &McuGeometry,
+    frame_components: &Vec<FrameComponent>,
+    precision: &u8,
+    format: &PixelFormat,
+    out: &mut [u8]
+) {
+    let max_vertical_factor = &geometry.max_vertical_factor;
+    let max_horizontal_factor = &geometry.max_horizontal_factor;
+    // Only the first component of a 2 component file is used
+    let coded_format = if frame_components.len() == 3 { PixelFormat::Rgb8 } else { PixelFormat::Gray8 };
+    let mut out_pixels = out.chunks_exact_mut(format.samples_per_pixel() as usize);
+    // Output is 8 bits per sample, so higher precision samples are
+    // reduced by dropping their least significant bits.
+    let sample_shift: u8 = precision - 8;
+    let mcu_width: usize = geometry.mcus_per_line;
+    for y in 0..*height {
+        let mcu_y = y / (8 * max_vertical_factor) as u16;
+        let block_y = y / 8;
+        let pixel_y = y % 8;
+        for x in 0..*width {
+            let mcu_x = x / (8 * max_horizontal_factor) as u16;
+            let block_x = x / 8;
+            let pixel_x = x % 8;
+            let mcu_idx: usize = mcu_y as usize * mcu_width + mcu_x as usize;
+            let pixel_idx: usize = pixel_y as usize * 8 + pixel_x as usize;
+            let mcu_block_y = block_y % *max_vertical_factor as u16;
+            let mcu_block_x = block_x % *max_horizontal_factor as u16;
+            let mcu_block_idx: usize = (mcu_block_y * *max_horizontal_factor as u16 + mcu_block_x) as usize;
+            let mut pixel: [u8; 3] = [0; 3];
+            for (sample, component) in pixel.iter_mut().zip(mcus[mcu_idx].iter()) {
+                *sample = (component[mcu_block_idx][pixel_idx] >> sample_shift) as u8;
+            }
+            let out_pixel = out_pixels.next().expect("(pixels_from_mcus) Output buffer is smaller than the image");
+            pack_pixel(&pixel, &coded_format, format, out_pixel);
+        }
+    }
+}
+
+fn planes_from_mcus(mcus: &Vec<Vec<Vec<[i16; 64]>>>, frame_header: &FrameHeader, geometry: &McuGeometry) -> Vec<Plane> {
+    return frame_header.components
+        .iter()
+        .enumerate()
+        .map(|(fc_idx, fc)| plane_from_mcus(mcus, frame_header, geometry, fc, &fc_idx))
+        .collect()
+}
+
+// component_idx is the position of fc's blocks within each mcu
+// The mcu and slot within it holding a component's block, counted in
+// blocks from the top left of the component's plane. Each mcu holds an h by
+// v group of the component's blocks, placed in the mcu's grid of max factor
+// slots.
+fn block_position(block_x: &usize, block_y: &usize, fc: &FrameComponent, geometry: &McuGeometry) -> (usize, usize) {
+    let h = fc.horizontal_sample_factor as usize;
+    let v = fc.vertical_sample_factor as usize;
+    let mcu_idx = (block_y / v) * geometry.mcus_per_line + block_x / h;
+    let slot = (block_y % v) * geometry.max_horizontal_factor as usize + block_x % h;
+    return (mcu_idx, slot)
+}
+
+fn plane_from_mcus(
+    mcus: &Vec<Vec<Vec<[i16; 64]>>>,
+    frame_header: &FrameHeader,
+    geometry: &McuGeometry,
+    fc: &FrameComponent,
+    component_idx: &usize
+) -> Plane {
+    let sample_shift: u8 = frame_header.precision - 8;
+    let (width, height) = frame_header.plane_size(fc);
+    let (width, height) = (width as usize, height as usize);
+    let mut samples: Vec<u8> = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            let (mcu_idx, slot) = block_position(&(x / 8), &(y / 8), fc, geometry);
+            let sample = mcus[mcu_idx][*component_idx][slot][(y % 8) * 8 + x % 8] >> sample_shift;
+            samples.push(sample as u8);
+        }
+    }
+    return Plane {
+        width: width as u16,
+        height: height as u16,
+        samples
+    }
+}
+
+// Repacks an image's pixels into another layout
+pub fn convert_pixels(image: &Image, format: &PixelFormat) -> Image {
+    let samples = image.format.samples_per_pixel() as usize;
+    let mut pixels: Vec<u8> = vec![0; image.width as usize * image.height as usize * format.samples_per_pixel() as usize];
+    let out_pixels = pixels.chunks_exact_mut(format.samples_per_pixel() as usize);
+    for (pixel, out_pixel) in image.pixels.chunks(samples).zip(out_pixels) {
+        pack_pixel(pixel, &image.format, format, out_pixel);
+    }
+    return Image {
+        width: image.width,
+        height: image.height,
+        total_components: format.samples_per_pixel(),
+        format: *format,
+        pixels,
+        truncated: image.truncated
+    }
+}
+
+// Writes one pixel given in the from layout to out in the to layout.
+// Grayscale samples are replicated across the color channels, and color is
+// reduced to gray with the JFIF luma weights (scaled by 2^16 as in
+// ycbcr_to_rgb_mcu).
+fn pack_pixel(pixel: &[u8], from: &PixelFormat, to: &PixelFormat, out: &mut [u8]) {
+    let [r, g, b] = match from {
+        PixelFormat::Gray8 => [pixel[0], pixel[0], pixel[0]],
+        PixelFormat::Rgb8 | PixelFormat::Rgba8 => [pixel[0], pixel[1], pixel[2]],
+        PixelFormat::Bgr8 | PixelFormat::Bgra8 => [pixel[2], pixel[1], pixel[0]]
+    };
+    match to {
+        PixelFormat::Rgb8 => out.copy_from_slice(&[r, g, b]),
+        PixelFormat::Rgba8 => out.copy_from_slice(&[r, g, b, 255]),
+        PixelFormat::Bgr8 => out.copy_from_slice(&[b, g, r]),
+        PixelFormat::Bgra8 => out.copy_from_slice(&[b, g, r, 255]),
+        PixelFormat::Gray8 => {
+            let luma: u32 = (19595 * r as u32 + 38470 * g as u32 + 7471 * b as u32 + (1 << 15)) >> 16;
+            out[0] = luma as u8;
+        }
+    }
+}
+
+// Tightly packed, top-down RGBA with an opaque alpha channel, as expected by
+// most texture upload and canvas APIs.
+pub fn to_rgba8(image: &Image) -> Vec<u8> {
+    return convert_pixels(image, &PixelFormat::Rgba8).pixels
+}
+
+// Tightly packed, top-down BGRA with an opaque alpha channel, which can be
+// passed to StretchDIBits with a negative height in its BITMAPINFO
+pub fn to_bgra8(image: &Image) -> Vec<u8> {
+    return convert_pixels(image, &PixelFormat::Bgra8).pixels
+}
+
+#[cfg(test)]
+mod tests {
+    // Tests always have std, even when the library is built without it
+    extern crate std;
+    use std::prelude::rust_2021::*;
+    use std::format;
+    use super::*;
+
+    #[test]
+    fn mcu_padding_rounds_up_to_whole_mcus() {
+        // Already a multiple of the mcu size
+        assert_eq!(mcu_padding(&16, &1), 0);
+        assert_eq!(mcu_padding(&16, &2), 0);
+        assert_eq!(mcu_padding(&16, &4), 0);
+        // 17 blocks wide at 4:2:0 needs one more block for 9 mcus
+        assert_eq!(mcu_padding(&17, &2), 1);
+        // 5 blocks wide at 4:1:1 needs three more blocks for 2 mcus
+        assert_eq!(mcu_padding(&5, &4), 3);
+        assert_eq!(mcu_padding(&7, &4), 1);
+        assert_eq!(mcu_padding(&1, &3), 2);
+    }
+
+    #[test]
+    fn mcu_geometry_covers_the_padded_image() {
+        // 61x37 at 4:2:0 is 8x5 blocks, padded to 8x6 for 4x3 mcus
+        let frame = Decoder::new(include_bytes!("images/gradient_420.jpg")).read_segments().unwrap();
+        let geometry = McuGeometry::new(&frame.frame_header);
+        assert_eq!((geometry.max_horizontal_factor, geometry.max_vertical_factor), (2, 2));
+        assert_eq!((geometry.width_blocks, geometry.height_blocks), (8, 5));
+        assert_eq!((geometry.width_blocks_padding, geometry.height_blocks_padding), (0, 1));
+        assert_eq!((geometry.mcus_per_line, geometry.total_mcus), (4, 12));
+        assert_eq!(geometry.blocks_per_component, vec![4, 1, 1]);
+        assert_eq!(geometry.component_offsets, vec![0, 4, 5]);
+        assert_eq!((geometry.blocks_per_mcu, geometry.mcu_size), (6, 4));
+    }
+
+    #[test]
+    fn more_than_65535_mcus() {
+        // A 65528x72 grayscale frame is 8191x9 = 73719 mcus of one block,
+        // more than a u16 count could hold. Every block is flat at 128 + 10,
+        // except the last one, which is 10 brighter, so any wrapped mcu
+        // index would put it in the wrong place.
+        let (width, height): (u16, u16) = (65528, 72);
+        let geometry_header = FrameHeader {
+            total_horizontal_lines: width,
+            total_vertical_lines: height,
+            total_components: 1,
+            components: vec![FrameComponent { id: 1, horizontal_sample_factor: 1, vertical_sample_factor: 1, quantization_table_selector: 0 }],
+            ..Default::default()
+        };
+        let geometry = McuGeometry::new(&geometry_header);
+        assert_eq!((geometry.mcus_per_line, geometry.total_mcus), (8191, 73719));
+
+        let dc = annex_k::huffman_table(&0, &0).unwrap();
+        let ac = annex_k::huffman_table(&1, &0).unwrap();
+        // Blocks with a DC difference of 0, and of 80 (category 7), which
+        // adds 10 to every sample
+        let flat = huffman_code(&dc, 0) + &huffman_code(&ac, 0x00);
+        let brighter = huffman_code(&dc, 7) + "1010000" + &huffman_code(&ac, 0x00);
+        let bits = brighter.clone() + &flat.repeat(geometry.total_mcus - 2) + &brighter;
+        let mut bytes: Vec<u8> = vec![0xff, Markers::SOI, 0xff, Markers::DQT, 0x00, 67, 0x00];
+        bytes.extend([1; 64]);
+        bytes.extend([0xff, Markers::SOF0, 0, 11, 8]);
+        bytes.extend(height.to_be_bytes());
+        bytes.extend(width.to_be_bytes());
+        bytes.extend([1, 1, 0x11, 0]);
+        bytes.extend([0xff, Markers::SOS, 0, 8, 1, 1, 0x00, 0, 63, 0]);
+        for byte in pack_bits(&bits) {
+            bytes.push(byte);
+            if byte == 0xff {
+                bytes.push(0x00);
+            }
+        }
+        bytes.extend([0xff, Markers::EOI]);
+        let options = DecodeOptions { default_tables: true, ..Default::default() };
+        let image = Decoder::with_options(&bytes, options).decode().unwrap();
+        assert_eq!((image.width, image.height), (width, height));
+        let last_row = &image.pixels[(height as usize - 1) * width as usize..];
+        assert!(last_row[..width as usize - 8].iter().all(|sample| *sample == 138));
+        assert!(last_row[width as usize - 8..].iter().all(|sample| *sample == 148));
+        assert!(image.pixels[..(height as usize - 8) * width as usize].iter().all(|sample| *sample == 138));
+    }
+
+    #[test]
+    fn bit_reader_pads_with_ones_unless_strict() {
+        let data = [0b1010_0000];
+        let mut padded = BitReader::new(&data, &false);
+        assert_eq!(padded.next_bits(&4), Some(0b1010));
+        assert_eq!(padded.next_bits(&8), Some(0b0000_1111));
+        assert_eq!(padded.next_bits(&4), Some(0b1111));
+
+        let mut strict = BitReader::new(&data, &true);
+        assert_eq!(strict.next_bits(&8), Some(0b1010_0000));
+        assert_eq!(strict.next_bit(), None);
+    }
+
+    #[test]
+    fn peek_bits_reads_ahead_without_consuming() {
+        let data: Vec<u8> = vec![0b1010_0000, 0b0110_1100];
+        let mut reader = BitReader::new(&data, &true);
+        reader.next_bits(&3);
+        assert_eq!(reader.peek_bits(&8), Some(0b0000_0011));
+        assert_eq!(reader.peek_bits(&13), Some(0b0_0000_0110_1100));
+        assert_eq!((reader.byte_idx, reader.bit_idx), (0, 3));
+        reader.skip_bits(&6);
+        assert_eq!(reader.next_bits(&4), Some(0b1101));
+        // Past the end, peeks pad like next_bit does
+        assert_eq!(reader.peek_bits(&4), None);
+        let mut padded = BitReader::new(&data, &false);
+        padded.skip_bits(&12);
+        assert_eq!(padded.peek_bits(&8), Some(0b1100_1111));
+    }
+
+    #[test]
+    fn huffman_lookup_agrees_with_the_walk() {
+        for (class, destination_id) in [(0, 0), (0, 1), (1, 0), (1, 1)] {
+            let table = annex_k::huffman_table(&class, &destination_id).unwrap();
+            let sizes = table.generate_size_table();
+            let codes = table.generate_code_table(&sizes).unwrap();
+            for (size, code) in sizes.iter().zip(codes.iter()) {
+                // Left align the code in 16 bits followed by zeros
+                let data: Vec<u8> = ((*code as u32) << (16 - size)).to_be_bytes()[2..].to_vec();
+                let mut walked = BitReader::new(&data, &true);
+                let symbol = walk_symbol(&mut walked, &table).unwrap();
+                assert_eq!(walked.byte_idx * 8 + walked.bit_idx, *size as usize);
+                let mut looked_up = BitReader::new(&data, &true);
+                assert_eq!(next_symbol(&mut looked_up, &table).unwrap(), symbol);
+                assert_eq!((looked_up.byte_idx, looked_up.bit_idx), (walked.byte_idx, walked.bit_idx));
+                let prefix = u16::from_be_bytes([data[0], data[1]]) >> (16 - HUFFMAN_LOOKUP_BITS);
+                let entry = table.lookup[prefix as usize];
+                if *size <= HUFFMAN_LOOKUP_BITS {
+                    assert_eq!(entry, (symbol, *size));
+                }
+                else {
+                    assert_eq!(entry.1, 0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn degenerate_huffman_tables() {
+        // A DHT segment for an AC table with the given code counts and values
+        let segment = |counts: [u8; 16], values: &[u8]| -> Vec<u8> {
+            let mut data: Vec<u8> = vec![0x10];
+            data.extend(counts);
+            data.extend(values);
+            return data
+        };
+
+        // No codes at all builds, but every code is rejected
+        let empty = segment([0; 16], &[]);
+        let mut table = HuffmanTable::default();
+        table.build(&(empty.len() as u16), &empty).unwrap();
+        assert!(table.generate_code_table(&Vec::new()).unwrap().is_empty());
+        assert!(table.maxcode.iter().all(Option::is_none));
+        let data: Vec<u8> = vec![0x00, 0x00, 0x00];
+        assert!(matches!(
+            next_symbol(&mut BitReader::new(&data, &true), &table),
+            Err(DecodeError::InvalidHuffmanCode)
+        ));
+
+        // One code of length 1 is the single code 0
+        let mut counts = [0; 16];
+        counts[0] = 1;
+        let single = segment(counts, &[0x42]);
+        let mut table = HuffmanTable::default();
+        table.build(&(single.len() as u16), &single).unwrap();
+        assert_eq!(table.generate_code_table(&table.generate_size_table()).unwrap(), vec![0]);
+        let data: Vec<u8> = vec![0x00, 0x00];
+        let mut bit_reader = BitReader::new(&data, &true);
+        assert_eq!(next_symbol(&mut bit_reader, &table).unwrap(), 0x42);
+        assert_eq!((bit_reader.byte_idx, bit_reader.bit_idx), (0, 1));
+        let data: Vec<u8> = vec![0xff, 0xff];
+        assert!(next_symbol(&mut BitReader::new(&data, &true), &table).is_err());
+
+        // Two codes of length 1 use up the tree, the second being all
+        // 1-bits, as libjpeg also accepts. A third can't fit.
+        counts[0] = 2;
+        let full = segment(counts, &[0x42, 0x43]);
+        let mut table = HuffmanTable::default();
+        table.build(&(full.len() as u16), &full).unwrap();
+        assert_eq!(table.generate_code_table(&table.generate_size_table()).unwrap(), vec![0, 1]);
+        let data: Vec<u8> = vec![0x80];
+        assert_eq!(next_symbol(&mut BitReader::new(&data, &true), &table).unwrap(), 0x43);
+        counts[0] = 3;
+        let over_subscribed = segment(counts, &[0x42, 0x43, 0x44]);
+        assert!(matches!(
+            HuffmanTable::default().build(&(over_subscribed.len() as u16), &over_subscribed),
+            Err(DecodeError::InvalidHuffmanTable)
+        ));
+    }
+
+    #[test]
+    fn dht_segments_may_hold_empty_tables() {
+        // gradient_420.jpg with a DHT segment after SOI, holding a DC table
+        // without codes followed by an AC table with one
+        let original: &[u8] = include_bytes!("images/gradient_420.jpg");
+        let with_dht = |dht: &[u8]| -> Vec<u8> {
+            let mut bytes: Vec<u8> = original[..2].to_vec();
+            bytes.extend([0xff, Markers::DHT]);
+            bytes.extend((dht.len() as u16 + 2).to_be_bytes());
+            bytes.extend(dht);
+            bytes.extend(&original[2..]);
+            return bytes
+        };
+        let mut dht: Vec<u8> = vec![0x02];
+        dht.extend([0; 16]);
+        dht.push(0x13);
+        dht.extend([1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x42]);
+        let bytes = with_dht(&dht);
+        let frame = Decoder::new(&bytes).read_segments().unwrap();
+        assert!(frame.dc_huffman_tables[2].as_ref().unwrap().huffman_values.is_empty());
+        assert_eq!(frame.ac_huffman_tables[3].as_ref().unwrap().huffman_values, vec![0x42]);
+        // The segments after it are still read
+        assert_eq!(frame.quantization_tables.iter().filter(|table| table.is_some()).count(), 2);
+        let expected = Decoder::new(original).decode().unwrap().pixels;
+        assert_eq!(Decoder::new(&bytes).decode().unwrap().pixels, expected);
+
+        // A segment that ends partway through its second table
+        let bytes = with_dht(&dht[..20]);
+        let frame = Decoder::new(&bytes).read_segments().unwrap();
+        assert!(frame.dc_huffman_tables[2].is_some());
+        assert!(frame.ac_huffman_tables[3].is_none());
+        assert_eq!(Decoder::new(&bytes).decode().unwrap().pixels, expected);
+        assert!(matches!(
+            Decoder::new(&bytes).strict(true).read_segments().unwrap_err().kind(),
+            DecodeError::SegmentLengthMismatch(Markers::DHT)
+        ));
+
+        // A segment that ends after the first byte of its first table
+        let bytes = with_dht(&dht[..1]);
+        let frame = Decoder::new(&bytes).read_segments().unwrap();
+        assert!(frame.dc_huffman_tables[2].is_none());
+        assert_eq!(Decoder::new(&bytes).decode().unwrap().pixels, expected);
+        assert!(matches!(
+            Decoder::new(&bytes).strict(true).read_segments().unwrap_err().kind(),
+            DecodeError::SegmentLengthMismatch(Markers::DHT)
+        ));
+    }
+
+    // Code for symbol in a table, as a string of bits
+    fn huffman_code(table: &HuffmanTable, symbol: u8) -> String {
+        let sizes = table.generate_size_table();
+        let codes = table.generate_code_table(&sizes).unwrap();
+        let k = table.huffman_values.iter().position(|value| *value == symbol).unwrap();
+        return format!("{:0width$b}", codes[k], width = sizes[k] as usize)
+    }
+
+    // A string of bits as bytes, with the last byte padded out with 1-bits
+    // as encoders do. Bytes of 0xff aren't stuffed.
+    fn pack_bits(bits: &str) -> Vec<u8> {
+        let padded = String::from(bits) + &"1".repeat((8 - bits.len() % 8) % 8);
+        return padded.as_bytes().chunks(8)
+            .map(|byte| u8::from_str_radix(core::str::from_utf8(byte).unwrap(), 2).unwrap())
+            .collect()
+    }
+
+    // An 8x8 frame with one flat block per component, coded with the Annex K
+    // luminance tables and quantizers of 1, so that a DC coefficient of 8 * k
+    // adds k to every sample. Each entry of scans lists the components, by
+    // index, that one scan codes.
+    fn flat_jpeg(dc_values: &[i16], scans: &[&[usize]]) -> Vec<u8> {
+        let dc = annex_k::huffman_table(&0, &0).unwrap();
+        let ac = annex_k::huffman_table(&1, &0).unwrap();
+        let total_components = dc_values.len() as u8;
+        let mut bytes: Vec<u8> = vec![0xff, Markers::SOI, 0xff, Markers::DQT, 0x00, 67, 0x00];
+        bytes.extend([1; 64]);
+        bytes.extend([0xff, Markers::SOF0]);
+        bytes.extend((8 + 3 * u16::from(total_components)).to_be_bytes());
+        bytes.extend([8, 0, 8, 0, 8, total_components]);
+        for id in 1..=total_components {
+            bytes.extend([id, 0x11, 0]);
+        }
+        for components in scans {
+            let mut bits = String::new();
+            for k in components.iter() {
+                // Negative values are coded as value - 1 in category bits
+                let value = dc_values[*k];
+                let category = 16 - value.unsigned_abs().leading_zeros() as usize;
+                bits += &huffman_code(&dc, category as u8);
+                if category > 0 {
+                    let coded = if value < 0 { value + (1 << category) - 1 } else { value };
+                    bits += &format!("{:0width$b}", coded, width = category);
+                }
+                bits += &huffman_code(&ac, 0x00);
+            }
+            bytes.extend([0xff, Markers::SOS]);
+            bytes.extend((6 + 2 * components.len() as u16).to_be_bytes());
+            bytes.push(components.len() as u8);
+            for k in components.iter() {
+                bytes.extend([*k as u8 + 1, 0x00]);
+            }
+            bytes.extend([0, 63, 0]);
+            bytes.extend(pack_bits(&bits));
+        }
+        bytes.extend([0xff, Markers::EOI]);
+        return bytes
+    }
+
+    #[test]
+    fn interleaved_scans_with_four_or_more_components() {
+        let options = DecodeOptions { default_tables: true, ..Default::default() };
+        for total_components in [4, 5] {
+            let dc_values: Vec<i16> = (0..total_components).map(|k| 16 * i16::from(k)).collect();
+            // A scan codes at most 4 components, so a fifth gets its own
+            let all: Vec<usize> = (0..total_components as usize).collect();
+            let scans: Vec<&[usize]> = all.chunks(4).collect();
+            let jpeg = flat_jpeg(&dc_values, &scans);
+            let planes = Decoder::with_options(&jpeg, options).decode_to_planar_yuv().unwrap();
+            assert_eq!(planes.len(), total_components as usize);
+            for (k, plane) in planes.iter().enumerate() {
+                assert_eq!((plane.width, plane.height), (8, 8));
+                assert!(plane.samples.iter().all(|sample| *sample == 128 + 2 * k as u8));
+            }
+            assert!(matches!(
+                Decoder::with_options(&jpeg, options).decode(),
+                Err(DecodeError::UnsupportedComponentCount(count)) if count == total_components
+            ));
+        }
+    }
+
+    #[test]
+    fn blocks_cover_each_plane() {
+        // 61x37 at 4:2:0 has 8x5 luma blocks and 4x3 blocks of each chroma
+        // plane, which is 31x19
+        let bytes = include_bytes!("images/gradient_420.jpg");
+        let blocks: Vec<(u8, usize, usize, [i16; 64])> = Decoder::new(bytes).blocks().unwrap().collect();
+        let planes = Decoder::new(bytes).decode_to_planar_yuv().unwrap();
+        let ids: Vec<u8> = Decoder::new(bytes).read_segments().unwrap().component_info().iter().map(|component| component.id).collect();
+        assert_eq!(blocks.len(), 40 + 12 + 12);
+        assert_eq!((blocks[39].0, blocks[39].1, blocks[39].2), (1, 56, 32));
+        assert_eq!((blocks[40].0, blocks[40].1, blocks[40].2), (2, 0, 0));
+        assert_eq!((blocks[63].0, blocks[63].1, blocks[63].2), (3, 24, 16));
+        // Samples within each plane match the planar decode
+        for (id, x, y, samples) in blocks.iter() {
+            let plane = &planes[ids.iter().position(|component_id| component_id == id).unwrap()];
+            for (idx, sample) in samples.iter().enumerate() {
+                let (sample_x, sample_y) = (x + idx % 8, y + idx / 8);
+                if sample_x < plane.width as usize && sample_y < plane.height as usize {
+                    assert_eq!(*sample, i16::from(plane.samples[sample_y * plane.width as usize + sample_x]));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn decode_rgb8_packs_rows_of_rgb() {
+        let color: &[u8] = include_bytes!("images/gradient_420.jpg");
+        let (pixels, width, height) = decode_rgb8(color).unwrap();
+        assert_eq!((width, height), (61, 37));
+        assert_eq!(pixels, Decoder::new(color).decode().unwrap().pixels);
+        // Grayscale samples are repeated in each channel
+        let gray: &[u8] = include_bytes!("images/gray_22.jpg");
+        let (pixels, width, height) = decode_rgb8(gray).unwrap();
+        let samples = Decoder::new(gray).decode().unwrap().pixels;
+        assert_eq!(pixels.len(), 3 * width as usize * height as usize);
+        for (pixel, sample) in pixels.chunks_exact(3).zip(samples.iter()) {
+            assert_eq!(pixel, [*sample; 3]);
+        }
+        assert!(matches!(decode_rgb8(&[0xff, 0xd9]).unwrap_err().kind(), DecodeError::NotAJpeg));
+    }
+
+    #[test]
+    fn only_8_and_12_bit_samples_are_decoded() {
+        let jpeg = flat_jpeg(&[80], &[&[0]]);
+        let options = DecodeOptions { default_tables: true, ..Default::default() };
+        let sof = jpeg.windows(2).position(|bytes| bytes == [0xff, Markers::SOF0]).unwrap();
+        for precision in [0, 7, 8, 12, 16] {
+            let mut bytes = jpeg.clone();
+            // P follows the marker and the length
+            bytes[sof + 4] = precision;
+            let decoded = Decoder::with_options(&bytes, options).decode();
+            if matches!(precision, 8 | 12) {
+                assert!(decoded.is_ok(), "{}-bit samples failed to decode", precision);
+            }
+            else {
+                assert!(matches!(decoded, Err(DecodeError::UnsupportedPrecision(p)) if p == precision));
+                assert!(matches!(decode_preview(&bytes), Err(DecodeError::UnsupportedPrecision(p)) if p == precision));
+                // The header can still be read
+                assert_eq!(Decoder::new(&bytes).read_metadata_only().unwrap().precision, precision);
+            }
+        }
+    }
+
+    #[test]
+    fn two_component_files_decode_their_first_component() {
+        let jpeg = flat_jpeg(&[80, 160], &[&[0, 1]]);
+        let options = DecodeOptions { default_tables: true, ..Default::default() };
+        let image = Decoder::with_options(&jpeg, options).decode().unwrap();
+        assert_eq!((image.format, image.total_components), (PixelFormat::Gray8, 1));
+        assert_eq!(image.pixels, vec![138; 64]);
+        let rgb = Decoder::with_options(&jpeg, options).with_output(PixelFormat::Rgb8).decode().unwrap();
+        assert_eq!(rgb.pixels, vec![138; 64 * 3]);
+        let second = Decoder::with_options(&jpeg, options).decode_component(&2).unwrap();
+        assert_eq!(second.samples, vec![148; 64]);
+    }
+
+    #[test]
+    fn each_completed_scan_is_reported() {
+        // Luma, Cb and Cr each in a scan of their own. Until the Cr scan,
+        // chroma is neutral and the image is gray.
+        let jpeg = flat_jpeg(&[160, 0, -80], &[&[0], &[1], &[2]]);
+        let options = DecodeOptions { default_tables: true, ..Default::default() };
+        let mut reported: Vec<Vec<u8>> = Vec::new();
+        let image = Decoder::with_options(&jpeg, options)
+            .decode_with_scan_callback(&mut |image| reported.push(image.pixels.clone()))
+            .unwrap();
+        assert_eq!(reported.len(), 3);
+        assert!(reported[0].iter().all(|sample| *sample == 148));
+        assert_eq!(reported[1], reported[0]);
+        assert_eq!(reported[2], image.pixels);
+        assert_eq!(&image.pixels[..3], &[134, 155, 148]);
+    }
+
+    #[test]
+    fn decode_into_reuses_a_caller_buffer() {
+        let jpeg: &[u8] = include_bytes!("images/gradient_422.jpg");
+        let decoder = Decoder::new(jpeg).with_output(PixelFormat::Rgba8);
+        let mut buffer: Vec<u8> = vec![0; 61 * 37 * 4];
+        decoder.decode_into(&mut buffer).unwrap();
+        assert_eq!(buffer, decoder.decode().unwrap().pixels);
+        // Decoding again overwrites the same buffer
+        buffer.fill(0);
+        decoder.decode_into(&mut buffer).unwrap();
+        assert_eq!(buffer, decoder.decode().unwrap().pixels);
+
+        let mut small: Vec<u8> = vec![0; 61 * 37 * 3];
+        assert!(matches!(
+            decoder.decode_into(&mut small),
+            Err(DecodeError::BufferTooSmall { required, got }) if required == 61 * 37 * 4 && got == 61 * 37 * 3
+        ));
+    }
+
+    #[test]
+    fn extend_covers_every_coefficient_length() {
+        // Each length codes -(2^L - 1) to -2^(L-1) and 2^(L-1) to 2^L - 1
+        // (refer to table F.1 in the spec)
+        assert_eq!(extend(&0, &0), 0);
+        for length in 1..=15u8 {
+            let half: i32 = 1 << (length - 1);
+            let full: i32 = (1 << length) - 1;
+            assert_eq!(extend(&0, &length), -full);
+            assert_eq!(extend(&(half as u16 - 1), &length), -half);
+            assert_eq!(extend(&(half as u16), &length), half);
+            assert_eq!(extend(&(full as u16), &length), full);
+        }
+    }
+
+    #[test]
+    fn twelve_bit_coefficients_reach_their_longest_lengths() {
+        // Tables giving every symbol a code of the same length, counting up
+        // from 0: DC symbols are coded as themselves in 5 bits, and the AC
+        // symbols are EOB, 0x0e and 0x01 in 4 bits
+        let table = |class: u8, length: usize, values: &[u8]| -> HuffmanTable {
+            let mut data: Vec<u8> = vec![class << 4];
+            let mut counts = [0; 16];
+            counts[length - 1] = values.len() as u8;
+            data.extend(counts);
+            data.extend(values);
+            let mut table = HuffmanTable::default();
+            table.build(&(data.len() as u16), &data).unwrap();
+            return table
+        };
+        let dc = table(0, 5, &(0..16).collect::<Vec<u8>>());
+        let ac = table(1, 4, &[0x00, 0x0e, 0x01]);
+        // A DC difference of -32767 (length 15, all zero bits), then AC
+        // values of 16383 and -16383 (length 14) and 1 and -1 (length 1)
+        let bits: String = [
+            "01111", &"0".repeat(15),
+            "0001", &"1".repeat(14),
+            "0001", &"0".repeat(14),
+            "0010", "1",
+            "0010", "0",
+            "0000"
+        ].concat();
+        let data = pack_bits(&bits);
+        let mut prev_dc: i16 = 0;
+        let block = decode_block(&mut prev_dc, &mut BitReader::new(&data, &true), &dc, &ac, &12).unwrap();
+        assert_eq!(block[..5], [-32767, 16383, -16383, 1, -1]);
+        assert_eq!(prev_dc, -32767);
+        // The same lengths are too long for 8-bit samples
+        assert!(matches!(
+            decode_block(&mut 0, &mut BitReader::new(&data, &true), &dc, &ac, &8),
+            Err(DecodeError::InvalidCoefficient)
+        ));
+    }
+
+    #[test]
+    fn zero_run_may_end_on_the_last_coefficient() {
+        let dc = annex_k::huffman_table(&0, &0).unwrap();
+        let ac = annex_k::huffman_table(&1, &0).unwrap();
+        // DC of 0, a 1 at index 1, two ZRLs up to index 33, 13 zeros and a 1
+        // at index 47, then a final ZRL covering indices 48 to 63
+        let bits: String = [
+            huffman_code(&dc, 0x00),
+            huffman_code(&ac, 0x01), "1".to_string(),
+            huffman_code(&ac, 0xf0),
+            huffman_code(&ac, 0xf0),
+            huffman_code(&ac, 0xd1), "1".to_string(),
+            huffman_code(&ac, 0xf0)
+        ].concat();
+        let data = pack_bits(&bits);
+        let mut reader = BitReader::new(&data, &true);
+        let block = decode_block(&mut 0, &mut reader, &dc, &ac, &8).unwrap();
+        let mut expected: [i16; 64] = [0; 64];
+        expected[1] = 1;
+        expected[47] = 1;
+        assert_eq!(block, expected);
+        assert_eq!(reader.byte_idx * 8 + reader.bit_idx, bits.len());
+    }
+
+    #[test]
+    fn rgba_output_is_opaque_and_unpadded() {
+        let rgb = Image { width: 3, height: 1, total_components: 3, format: PixelFormat::Rgb8, pixels: vec![1, 2, 3, 4, 5, 6, 7, 8, 9], truncated: false };
+        assert_eq!(to_rgba8(&rgb), vec![1, 2, 3, 255, 4, 5, 6, 255, 7, 8, 9, 255]);
+        let gray = Image { width: 1, height: 2, total_components: 1, format: PixelFormat::Gray8, pixels: vec![10, 20], truncated: false };
+        assert_eq!(to_rgba8(&gray), vec![10, 10, 10, 255, 20, 20, 20, 255]);
+        assert_eq!(to_bgra8(&rgb), vec![3, 2, 1, 255, 6, 5, 4, 255, 9, 8, 7, 255]);
+        assert_eq!(to_bgra8(&gray), vec![10, 10, 10, 255, 20, 20, 20, 255]);
+    }
+
+    #[test]
+    fn output_format_selects_the_pixel_layout() {
+        let jpeg: &[u8] = include_bytes!("images/gradient_422.jpg");
+        let rgb = Decoder::new(jpeg).decode().unwrap();
+        assert_eq!(rgb.format, PixelFormat::Rgb8);
+        let bgr = Decoder::new(jpeg).with_output(PixelFormat::Bgr8).strict(false).decode().unwrap();
+        assert_eq!(bgr.total_components, 3);
+        assert_eq!(&bgr.pixels[..3], &[rgb.pixels[2], rgb.pixels[1], rgb.pixels[0]]);
+        let rgba = Decoder::new(jpeg).with_output(PixelFormat::Rgba8).decode().unwrap();
+        assert_eq!(rgba.pixels.len(), 61 * 37 * 4);
+        assert_eq!(&rgba.pixels[..4], &[rgb.pixels[0], rgb.pixels[1], rgb.pixels[2], 255]);
+        let bgra = Decoder::new(jpeg).with_output(PixelFormat::Bgra8).decode().unwrap();
+        assert_eq!((bgra.total_components, bgra.pixels.len()), (4, 61 * 37 * 4));
+        assert_eq!(&bgra.pixels[..4], &[rgb.pixels[2], rgb.pixels[1], rgb.pixels[0], 255]);
+        let gray = Decoder::new(jpeg).with_output(PixelFormat::Gray8).decode().unwrap();
+        assert_eq!((gray.total_components, gray.pixels.len()), (1, 61 * 37));
+        // Pure white and black keep their value when reduced to gray
+        let extremes = Image { width: 2, height: 1, total_components: 3, format: PixelFormat::Rgb8, pixels: vec![255, 255, 255, 0, 0, 0], truncated: false };
+        assert_eq!(convert_pixels(&extremes, &PixelFormat::Gray8).pixels, vec![255, 0]);
+    }
+
+    // Reference pixels come from another decoder. Each sample may differ by
+    // up to tolerance, which only needs to be more than rounding where the
+    // chroma upsampling differs: the reference blends neighboring chroma
+    // samples where this decoder repeats them. A misplaced or doubly
+    // stretched chroma block would still be far off on these gradients.
+    fn assert_matches_reference(jpeg: &[u8], reference: &[u8], tolerance: &u8) {
+        let image = Decoder::new(jpeg).decode().unwrap();
+        assert_samples_match_reference(&image.width, &image.height, &image.total_components, &image.pixels, reference, tolerance);
+    }
+
+    fn assert_samples_match_reference(width: &u16, height: &u16, total_components: &u8, pixels: &[u8], reference: &[u8], tolerance: &u8) {
+        // Grayscale references are PGM (P5) files, color ones PPM (P6). The
+        // header is the magic number, the dimensions and the max sample,
+        // each ending in a newline.
+        let mut fields = reference.splitn(4, |byte| *byte == b'\n');
+        let magic = fields.next().unwrap();
+        let dimensions: Vec<u16> = core::str::from_utf8(fields.next().unwrap()).unwrap()
+            .split(' ')
+            .map(|dimension| dimension.parse().unwrap())
+            .collect();
+        assert_eq!(fields.next().unwrap(), b"255");
+        let samples = fields.next().unwrap();
+        let reference_components = if magic == b"P5" { 1 } else { 3 };
+        assert_eq!((*width, *height, *total_components), (dimensions[0], dimensions[1], reference_components));
+        assert_eq!(pixels.len(), samples.len());
+        for (idx, (sample, expected)) in pixels.iter().zip(samples).enumerate() {
+            assert!(sample.abs_diff(*expected) <= *tolerance, "sample {} is {} rather than {}", idx, sample, expected);
+        }
+    }
+
+    #[test]
+    fn photo_matches_its_golden_image() {
+        // The golden is the reference decoder's luma plane for a photo.
+        // Only luma is compared: the reference upsamples chroma with a
+        // triangle filter rather than replicating samples, which moves the
+        // color of sharp chroma edges by far more than rounding.
+        let planes = Decoder::new(include_bytes!("images/guy.jpg")).decode_to_planar_yuv().unwrap();
+        let luma = &planes[0];
+        assert_samples_match_reference(&luma.width, &luma.height, &1, &luma.samples, include_bytes!("images/guy.pgm"), &1);
+    }
+
+    #[test]
+    fn decodes_422_chroma_at_full_horizontal_resolution() {
+        assert_matches_reference(
+            include_bytes!("images/gradient_422.jpg"),
+            include_bytes!("images/gradient_422.ppm"),
+            &8
+        );
+    }
+
+    #[test]
+    fn decodes_grayscale_with_sampling_factors_above_one() {
+        // A single component scan is non-interleaved, so the 2x2 sampling
+        // factors only change how its blocks are grouped into 16x16 mcus.
+        assert_matches_reference(
+            include_bytes!("images/gray_22.jpg"),
+            include_bytes!("images/gray_22.pgm"),
+            &1
+        );
+    }
+
+    #[test]
+    fn component_scans_assemble_like_an_interleaved_scan() {
+        // The same 52x30 4:2:0 image, coded once as a single interleaved scan
+        // and once as one scan per component with restart intervals. The
+        // luma scan covers 7 blocks per row while its mcus span 8.
+        let interleaved = Decoder::new(include_bytes!("images/scans_420_interleaved.jpg"));
+        let separate = Decoder::new(include_bytes!("images/scans_420_separate.jpg"));
+        assert_eq!(separate.read_segments().unwrap().scans.len(), 3);
+        assert_eq!(separate.decode().unwrap().pixels, interleaved.decode().unwrap().pixels);
+
+        // Every block is flat, so one sample shows the block landed where
+        // the encoder put it
+        let planes = separate.decode_to_planar_yuv().unwrap();
+        assert_eq!(planes[0].samples[3 * 8 * 52 + 6 * 8], 201);
+        assert_eq!(planes[1].samples[8 * 26 + 3 * 8], 106);
+    }
+
+    #[test]
+    fn decodes_sequential_arithmetic_coding() {
+        // Transcoded from the Huffman coded fixtures, so the coefficients
+        // and therefore the pixels are the same. The second file has restart
+        // intervals, DAC conditioning for both classes and stuffed bytes.
+        for (arithmetic, huffman) in [
+            (&include_bytes!("images/gradient_420_arithmetic.jpg")[..], &include_bytes!("images/gradient_420.jpg")[..]),
+            (&include_bytes!("images/gradient_420_restart_arithmetic.jpg")[..], &include_bytes!("images/gradient_420_restart.jpg")[..])
+        ] {
+            let frame = Decoder::new(arithmetic).read_segments().unwrap();
+            assert_eq!(frame.coding_process(), Some(CodingProcess::Arithmetic));
+            assert_eq!(Decoder::new(arithmetic).decode().unwrap().pixels, Decoder::new(huffman).decode().unwrap().pixels);
+        }
+        let frame = Decoder::new(include_bytes!("images/gradient_420_restart_arithmetic.jpg")).read_segments().unwrap();
+        assert_eq!(frame.arithmetic_tables.len(), 4);
+        assert_eq!((frame.arithmetic_tables[1].class, frame.arithmetic_tables[1].destination_id, frame.arithmetic_tables[1].value), (0, 1, 0x12));
+
+        // Each table takes 2 bytes, so an odd length leaves one short
+        let original: &[u8] = include_bytes!("images/gradient_420_restart_arithmetic.jpg");
+        let dac = segments(original).find(|segment| segment.marker == Markers::DAC).unwrap();
+        let mut odd: Vec<u8> = original.to_vec();
+        odd[dac.offset + 3] -= 1;
+        odd.remove(dac.offset + 2 + dac.length as usize - 1);
+        assert!(matches!(
+            Decoder::new(&odd).read_segments().unwrap_err().kind(),
+            DecodeError::SegmentLengthMismatch(Markers::DAC)
+        ));
+
+        // Progressive arithmetic coding is still unsupported
+        let mut progressive = include_bytes!("images/gradient_420_arithmetic.jpg").to_vec();
+        let sof = progressive.windows(2).position(|bytes| bytes == [0xff, Markers::SOF9]).unwrap();
+        progressive[sof + 1] = Markers::SOF10;
+        assert!(matches!(Decoder::new(&progressive).decode(), Err(DecodeError::Unsupported(CodingProcess::Arithmetic))));
+    }
+
+    #[test]
+    fn zigzag_round_trips_every_index() {
+        for idx in 0..64 {
+            assert_eq!(zigzag::from_zigzag(&zigzag::to_zigzag(&idx)), idx);
+            assert_eq!(zigzag::to_zigzag(&zigzag::from_zigzag(&idx)), idx);
+        }
+        // The second coefficient is one step right of the DC and the third
+        // one step down
+        assert_eq!(zigzag::from_zigzag(&1), 1);
+        assert_eq!(zigzag::from_zigzag(&2), 8);
+        let block: [i16; 64] = core::array::from_fn(|idx| idx as i16);
+        assert_eq!(zigzag::zigzag_order(&zigzag::natural_order(&block)), block);
+    }
+
+    #[test]
+    fn quantizers_line_up_with_coefficients() {
+        // Sharp horizontal and vertical edges exercise coefficients on both
+        // sides of the zigzag, which a transposed quantizer would distort.
+        assert_matches_reference(
+            include_bytes!("images/pattern_444.jpg"),
+            include_bytes!("images/pattern_444.ppm"),
+            &2
+        );
+    }
+
+    #[test]
+    fn preview_of_a_progressive_file_matches_its_baseline_twin() {
+        // The same image encoded both ways has the same DC coefficients.
+        // The progressive file codes them in a DC scan per component
+        // followed by AC scans, which the preview skips.
+        let progressive = include_bytes!("images/gradient_420_progressive.jpg");
+        let baseline = include_bytes!("images/gradient_420.jpg");
+        assert!(matches!(Decoder::new(progressive).decode(), Err(DecodeError::Unsupported(CodingProcess::Progressive))));
+        let preview = decode_preview(progressive).unwrap();
+        assert_eq!((preview.width, preview.height, preview.format), (8, 5, PixelFormat::Rgb8));
+        assert_eq!(preview.pixels, decode_preview(baseline).unwrap().pixels);
+    }
+
+    #[test]
+    fn preview_pixels_are_the_means_of_their_areas() {
+        // A grayscale file, so that each pixel's one sample is the mean of
+        // its 8x8 area. In color previews, subsampled chroma only has one
+        // value per block. Areas along the right and bottom edges are left
+        // out, since their means also take in the padding.
+        let bytes = include_bytes!("images/gray_22.jpg");
+        let preview = decode_preview(bytes).unwrap();
+        let image = Decoder::new(bytes).decode().unwrap();
+        assert_eq!((preview.width, preview.height, preview.format), (8, 5, PixelFormat::Gray8));
+        for y in 0..(image.height / 8) as usize {
+            for x in 0..(image.width / 8) as usize {
+                let mut sum: u32 = 0;
+                for image_y in y * 8..y * 8 + 8 {
+                    for image_x in x * 8..x * 8 + 8 {
+                        sum += u32::from(image.pixels[image_y * image.width as usize + image_x]);
+                    }
+                }
+                let sample = preview.pixels[y * preview.width as usize + x];
+                assert!(u32::from(sample).abs_diff((sum + 32) / 64) <= 1, "pixel ({}, {}) is {} rather than about {}", x, y, sample, (sum + 32) / 64);
+            }
+        }
+    }
+
+    #[test]
+    fn preview_adds_dc_refinement_bits() {
+        // An 8x8 progressive frame whose first scan codes the DC
+        // coefficients of all three components, interleaved, with the low
+        // 4 bits dropped (Al = 4). A refinement scan then adds bit 3 of
+        // each, and an AC scan follows, which the preview skips. With
+        // quantizers of 1, a luma DC coefficient of 8 * 21 is a mean of
+        // 128 + 21 and neutral chroma leaves the pixel gray.
+        let dc = annex_k::huffman_table(&0, &0).unwrap();
+        let mut bytes: Vec<u8> = vec![0xff, Markers::SOI, 0xff, Markers::DQT, 0x00, 67, 0x00];
+        bytes.extend([1; 64]);
+        bytes.extend([0xff, Markers::DHT]);
+        bytes.extend((19 + dc.huffman_values.len() as u16).to_be_bytes());
+        bytes.push(0x00);
+        bytes.extend(dc.huffman_size_lengths);
+        bytes.extend(dc.huffman_values.iter());
+        bytes.extend([0xff, Markers::SOF2, 0, 17, 8, 0, 8, 0, 8, 3, 1, 0x11, 0, 2, 0x11, 0, 3, 0x11, 0]);
+        let scan = |bytes: &mut Vec<u8>, components: &[u8], parameters: [u8; 3], bits: String| {
+            bytes.extend([0xff, Markers::SOS]);
+            bytes.extend((6 + 2 * components.len() as u16).to_be_bytes());
+            bytes.push(components.len() as u8);
+            for id in components.iter() {
+                bytes.extend([*id, 0x00]);
+            }
+            bytes.extend(parameters);
+            bytes.extend(pack_bits(&bits));
+        };
+        // 168 >> 4 is 10, coded in category 4. Chroma differences are 0.
+        let first = huffman_code(&dc, 4) + "1010" + &huffman_code(&dc, 0) + &huffman_code(&dc, 0);
+        scan(&mut bytes, &[1, 2, 3], [0, 0, 0x04], first);
+        scan(&mut bytes, &[1, 2, 3], [0, 0, 0x43], String::from("100"));
+        scan(&mut bytes, &[1], [1, 63, 0x00], String::from("0000"));
+        bytes.extend([0xff, Markers::EOI]);
+        let preview = decode_preview(&bytes).unwrap();
+        assert_eq!((preview.width, preview.height), (1, 1));
+        assert_eq!(preview.pixels, [149, 149, 149]);
+        // Without the refinement scan, bit 3 is missing
+        let refinement = bytes.windows(2).rposition(|bytes| bytes == [0xff, Markers::SOS]).unwrap();
+        let first_scan_end = bytes[..refinement].windows(2).rposition(|bytes| bytes == [0xff, Markers::SOS]).unwrap();
+        let mut unrefined = bytes[..first_scan_end].to_vec();
+        unrefined.extend([0xff, Markers::EOI]);
+        assert_eq!(decode_preview(&unrefined).unwrap().pixels, [148, 148, 148]);
+    }
+
+    #[test]
+    fn decodes_420_chroma_without_block_offset() {
+        assert_matches_reference(
+            include_bytes!("images/gradient_420.jpg"),
+            include_bytes!("images/gradient_420.ppm"),
+            &8
+        );
+    }
+
+    #[test]
+    fn output_is_cropped_to_the_image_size() {
+        // 100 pixels is 13 blocks, padded to 14 for 7 mcus of 16 pixels, so
+        // the last 12 rows and columns of the mcus aren't part of the image
+        let jpeg: &[u8] = include_bytes!("images/gradient_420_100x100.jpg");
+        assert_matches_reference(jpeg, include_bytes!("images/gradient_420_100x100.ppm"), &8);
+        let gray = Decoder::new(jpeg).with_output(PixelFormat::Gray8).decode().unwrap();
+        assert_eq!((gray.width, gray.height, gray.pixels.len()), (100, 100, 100 * 100));
+        let sizes: Vec<(u16, u16, usize)> = Decoder::new(jpeg).decode_to_planar_yuv().unwrap()
+            .iter()
+            .map(|plane| (plane.width, plane.height, plane.samples.len()))
+            .collect();
+        assert_eq!(sizes, vec![(100, 100, 100 * 100), (50, 50, 50 * 50), (50, 50, 50 * 50)]);
+    }
+
+    #[test]
+    fn restart_intervals_decode_like_a_single_segment() {
+        // Same encoder settings, but with an RSTn marker every 3 MCUs
+        let plain = Decoder::new(include_bytes!("images/gradient_420.jpg")).decode().unwrap();
+        let restart = Decoder::new(include_bytes!("images/gradient_420_restart.jpg")).strict(true).decode().unwrap();
+        assert_eq!(restart.pixels, plain.pixels);
+    }
+
+    #[test]
+    fn restart_markers_without_dri_still_reset_the_decoder() {
+        let original: &[u8] = include_bytes!("images/gradient_420_restart.jpg");
+        let dri = segments(original).find(|segment| segment.marker == Markers::DRI).unwrap();
+        let mut bytes: Vec<u8> = original.to_vec();
+        bytes.drain(dri.offset..dri.offset + 2 + dri.length as usize);
+        let frame = Decoder::new(&bytes).read_segments().unwrap();
+        assert!(frame.restart_interval.is_none());
+        let expected = Decoder::new(original).decode().unwrap();
+        assert_eq!(Decoder::new(&bytes).decode().unwrap().pixels, expected.pixels);
+    }
+
+    #[test]
+    fn truncated_files_decode_what_was_received() {
+        // 200 bytes of the scan cover the first row of 4 mcus but not the
+        // last of the 3 rows
+        let original: &[u8] = include_bytes!("images/gradient_420.jpg");
+        let sos = segments(original).find(|segment| segment.marker == Markers::SOS).unwrap();
+        let cut = &original[..sos.offset + 2 + sos.length as usize + 200];
+        assert!(Decoder::new(cut).decode().is_err());
+
+        let options = DecodeOptions { allow_truncated: true, ..Default::default() };
+        let partial = Decoder::with_options(cut, options).decode().unwrap();
+        let whole = Decoder::new(original).decode().unwrap();
+        assert!(partial.truncated);
+        assert!(!whole.truncated);
+        let row = 61 * 3;
+        assert_eq!(partial.pixels[..16 * row], whole.pixels[..16 * row]);
+        assert!(partial.pixels[32 * row..].iter().all(|sample| *sample == 128));
+
+        // Complete files are unaffected
+        let complete = Decoder::with_options(original, options).decode().unwrap();
+        assert!(!complete.truncated);
+        assert_eq!(complete.pixels, whole.pixels);
+    }
+
+    #[test]
+    fn strict_mode_requires_every_restart_marker() {
+        // Without RST2 the last interval runs on from the one before it
+        let original: &[u8] = include_bytes!("images/gradient_420_restart.jpg");
+        let rst2 = segments(original).find(|segment| segment.marker == Markers::RST2).unwrap();
+        let mut damaged: Vec<u8> = original.to_vec();
+        damaged.drain(rst2.offset..rst2.offset + 2);
+        let error = Decoder::new(&damaged).strict(true).decode().unwrap_err();
+        assert!(matches!(error.kind(), DecodeError::MissingRestartMarker(Markers::RST2)));
+        assert!(matches!(error, DecodeError::At { scan: Some(0), .. }));
+        // A lenient reader aligns to the next byte and carries on
+        assert!(Decoder::new(&damaged).decode().is_ok());
+    }
+
+    #[test]
+    fn recover_mode_skips_intervals_with_lost_markers() {
+        // 12 mcus of 16x16 pixels in 4 restart intervals of 3 mcus. Dropping
+        // RST1 leaves the third interval (mcus 6 to 8) without a start.
+        let original: &[u8] = include_bytes!("images/gradient_420_restart.jpg");
+        let rst1 = segments(original).find(|segment| segment.marker == Markers::RST1).unwrap();
+        let mut damaged: Vec<u8> = original.to_vec();
+        damaged.drain(rst1.offset..rst1.offset + 2);
+        assert!(matches!(
+            Decoder::new(&damaged).decode().unwrap_err().kind(),
+            DecodeError::RestartMarkerOutOfSequence { expected: Markers::RST1, found: Markers::RST2 }
+        ));
+
+        let options = DecodeOptions { recover: true, ..Default::default() };
+        let recovered = Decoder::with_options(&damaged, options).decode().unwrap();
+        let expected = Decoder::new(original).decode().unwrap();
+        let pixel = |image: &Image, x: usize, y: usize| -> Vec<u8> {
+            let idx = (y * 61 + x) * 3;
+            image.pixels[idx..idx + 3].to_vec()
+        };
+        for (mcu_x, mcu_y) in [(0, 0), (3, 0), (1, 1), (1, 2), (3, 2)] {
+            assert_eq!(pixel(&recovered, mcu_x * 16 + 5, mcu_y * 16 + 2), pixel(&expected, mcu_x * 16 + 5, mcu_y * 16 + 2));
+        }
+        for (mcu_x, mcu_y) in [(2, 1), (3, 1), (0, 2)] {
+            assert_eq!(pixel(&recovered, mcu_x * 16 + 5, mcu_y * 16 + 2), vec![128, 128, 128]);
+        }
+    }
+
+    #[test]
+    fn planar_output_keeps_chroma_at_its_own_resolution() {
+        let planes = Decoder::new(include_bytes!("images/gradient_420.jpg")).decode_to_planar_yuv().unwrap();
+        let sizes: Vec<(u16, u16, usize)> = planes.iter().map(|plane| (plane.width, plane.height, plane.samples.len())).collect();
+        assert_eq!(sizes, vec![(61, 37, 61 * 37), (31, 19, 31 * 19), (31, 19, 31 * 19)]);
+
+        // Without subsampling, converting the planes gives the rgb output
+        let jpeg: &[u8] = include_bytes!("images/pattern_444.jpg");
+        let planes = Decoder::new(jpeg).decode_to_planar_yuv().unwrap();
+        let rgb = Decoder::new(jpeg).decode().unwrap();
+        for (idx, pixel) in rgb.pixels.chunks(3).enumerate() {
+            let y = planes[0].samples[idx] as f32;
+            let cb = planes[1].samples[idx] as f32 - 128.0;
+            let cr = planes[2].samples[idx] as f32 - 128.0;
+            let red = (y + 1.402 * cr).round().clamp(0.0, 255.0) as u8;
+            let blue = (y + 1.772 * cb).round().clamp(0.0, 255.0) as u8;
+            assert_eq!((pixel[0], pixel[2]), (red, blue));
+        }
+    }
+
+    #[test]
+    fn single_components_decode_like_their_planes() {
+        let decoder = Decoder::new(include_bytes!("images/gradient_420.jpg"));
+        let planes = decoder.decode_to_planar_yuv().unwrap();
+        for (id, plane) in (1..=3).zip(planes.iter()) {
+            let component = decoder.decode_component(&id).unwrap();
+            assert_eq!((component.width, component.height), (plane.width, plane.height));
+            assert_eq!(component.samples, plane.samples);
+        }
+        assert!(matches!(decoder.decode_component(&4), Err(DecodeError::UnknownComponent(4))));
+
+        // Sampling factors of 0 or above 4 are rejected with the SOF segment
+        let original: &[u8] = include_bytes!("images/gradient_420.jpg");
+        let sof = segments(original).find(|segment| segment.marker == Markers::SOF0).unwrap();
+        for (factors, horizontal, vertical) in [(0x10, 1, 0), (0x51, 5, 1)] {
+            let mut invalid: Vec<u8> = original.to_vec();
+            invalid[sof.offset + 14] = factors;
+            let decoder = Decoder::new(&invalid);
+            for error in [decoder.read_segments().unwrap_err(), decoder.decode().unwrap_err(), decoder.decode_component(&1).unwrap_err()] {
+                assert!(matches!(
+                    error.kind(),
+                    DecodeError::InvalidSamplingFactor { component: 2, horizontal: h, vertical: v } if (*h, *v) == (horizontal, vertical)
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn idct_paths_agree_with_the_direct_sum() {
+        let basis = idct::Basis::new();
+        let selected = idct::select();
+        let mut seed: u32 = 1;
+        for _ in 0..200 {
+            let mut block: [i16; 64] = [0; 64];
+            for coefficient in block.iter_mut() {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                // Mostly zeros, like real blocks after quantization
+                if seed >> 28 < 5 {
+                    *coefficient = ((seed >> 16) % 2048) as i16 - 1024;
+                }
+            }
+            let samples = idct::scalar(&block, &basis);
+            assert_eq!(selected(&block, &basis), samples);
+            for y in 0..8 {
+                for x in 0..8 {
+                    let mut sum: f64 = 0.0;
+                    for v in 0..8 {
+                        for u in 0..8 {
+                            let cu = if u == 0 { 1.0 / 2_f64.sqrt() } else { 1.0 };
+                            let cv = if v == 0 { 1.0 / 2_f64.sqrt() } else { 1.0 };
+                            sum += cu * cv * block[v * 8 + u] as f64
+                                * ((2 * x + 1) as f64 * u as f64 * core::f64::consts::PI / 16.0).cos()
+                                * ((2 * y + 1) as f64 * v as f64 * core::f64::consts::PI / 16.0).cos();
+                        }
+                    }
+                    assert!((sum / 4.0 - samples[y * 8 + x] as f64).abs() <= 0.5 + 1e-9);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn level_shift_clamps_to_the_sample_range() {
+        // Overshoot on high contrast edges can saturate the idct at either
+        // end of the i16 range. Grayscale has no color conversion to clamp
+        // it later, so the level shift has to.
+        let bright: [i16; 64] = [i16::MAX; 64];
+        let dark: [i16; 64] = [i16::MIN; 64];
+        for (precision, max_sample) in [(8, 255), (12, 4095)] {
+            let shifted = level_shift(&vec![vec![vec![bright, dark]]], &precision);
+            assert!(shifted[0][0][0].iter().all(|sample| *sample == max_sample));
+            assert!(shifted[0][0][1].iter().all(|sample| *sample == 0));
+        }
+    }
+
+    #[test]
+    fn color_conversion_rejects_malformed_mcus() {
+        // 4:2:0 mcus hold 4 blocks per component once upscaled
+        let frame = Decoder::new(include_bytes!("images/gradient_420.jpg")).read_segments().unwrap();
+        let geometry = McuGeometry::new(&frame.frame_header);
+        let convert = |mcus: &Vec<Vec<Vec<[i16; 64]>>>, total_components: u8| {
+            return ycbcr_to_rgb_mcu(mcus, &geometry, &total_components, &8, &ColorConversion::Float)
+        };
+        let gray: Vec<Vec<Vec<[i16; 64]>>> = vec![vec![vec![[128; 64]; 4]; 3]; geometry.total_mcus];
+        let rgb = convert(&gray, 3).unwrap();
+        assert!(rgb.iter().flatten().flatten().all(|block| *block == [128; 64]));
+
+        let mut missing_component = gray.clone();
+        missing_component[5].pop();
+        assert!(matches!(convert(&missing_component, 3), Err(DecodeError::BlockCountMismatch { expected: 4, got: 0 })));
+        let mut missing_block = gray.clone();
+        missing_block[11][1].pop();
+        assert!(matches!(convert(&missing_block, 3), Err(DecodeError::BlockCountMismatch { expected: 4, got: 3 })));
+        assert!(matches!(convert(&gray, 4), Err(DecodeError::UnsupportedComponentCount(4))));
+    }
+
+    #[test]
+    fn upscale_block_repeats_each_sample_over_its_area() {
+        let block: [i16; 64] = core::array::from_fn(|idx| idx as i16);
+        for (h, v) in [(1, 1), (2, 1), (1, 2), (2, 2)] {
+            let upscaled = upscale_block(&block, h, v);
+            assert_eq!(upscaled.len(), h * v);
+            // Blocks are ordered left-to-right, top-to-bottom, and together
+            // cover 8h x 8v pixels. Each pixel takes the sample it falls on
+            // when the block is stretched over that area.
+            for y in 0..8 * v {
+                for x in 0..8 * h {
+                    let upscaled_sample = upscaled[(y / 8) * h + x / 8][(y % 8) * 8 + x % 8];
+                    assert_eq!(upscaled_sample, block[(y / v) * 8 + x / h], "({}, {}) at {}x{}", x, y, h, v);
+                }
+            }
+        }
+
+        // 4:2:2 doubles each sample across, keeping every row
+        let wide = upscale_block(&block, 2, 1);
+        assert_eq!(wide[0][..8], [0, 0, 1, 1, 2, 2, 3, 3]);
+        assert_eq!(wide[0][8..16], [8, 8, 9, 9, 10, 10, 11, 11]);
+        assert_eq!(wide[1][..8], [4, 4, 5, 5, 6, 6, 7, 7]);
+        assert_eq!(wide[1][56..], [60, 60, 61, 61, 62, 62, 63, 63]);
+
+        // 4:4:0 doubles each row, keeping every sample within it
+        let tall = upscale_block(&block, 1, 2);
+        assert_eq!(tall[0][..16], [0, 1, 2, 3, 4, 5, 6, 7, 0, 1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(tall[0][56..], [24, 25, 26, 27, 28, 29, 30, 31]);
+        assert_eq!(tall[1][..8], [32, 33, 34, 35, 36, 37, 38, 39]);
+        assert_eq!(tall[1][56..], [56, 57, 58, 59, 60, 61, 62, 63]);
+
+        // 4:2:0 does both, so each quarter of the block fills one block
+        let both = upscale_block(&block, 2, 2);
+        assert_eq!(both[0][..8], [0, 0, 1, 1, 2, 2, 3, 3]);
+        assert_eq!(both[0][8..16], [0, 0, 1, 1, 2, 2, 3, 3]);
+        assert_eq!(both[1][..8], [4, 4, 5, 5, 6, 6, 7, 7]);
+        assert_eq!(both[2][..8], [32, 32, 33, 33, 34, 34, 35, 35]);
+        assert_eq!(both[3][56..], [60, 60, 61, 61, 62, 62, 63, 63]);
+
+        // Without scaling the block comes back as is
+        assert_eq!(upscale_block(&block, 1, 1), vec![block]);
+    }
+
+    #[test]
+    fn fixed_point_color_conversion_tracks_float() {
+        let bytes: &[u8] = include_bytes!("images/guy.jpg");
+        let float = Decoder::new(bytes).decode().unwrap();
+        let options = DecodeOptions { color_conversion: ColorConversion::FixedPoint, ..Default::default() };
+        let fixed = Decoder::with_options(bytes, options).decode().unwrap();
+        assert_eq!(fixed.pixels.len(), float.pixels.len());
+        // Only rounding may differ between the two
+        for (a, b) in fixed.pixels.iter().zip(float.pixels.iter()) {
+            assert!(a.abs_diff(*b) <= 1);
+        }
+    }
+
+    #[test]
+    fn lenient_mode_skips_stray_bytes_and_missing_eoi() {
+        let original: &[u8] = include_bytes!("images/guy.jpg");
+        // Stray padding before the first DQT segment and no EOI
+        let dqt = original.windows(2).position(|m| m == [0xff, Markers::DQT]).unwrap();
+        let mut messy: Vec<u8> = original[..dqt].to_vec();
+        messy.extend([0x00, 0x00, 0x12]);
+        messy.extend(&original[dqt..original.len() - 2]);
+
+        let expected = Decoder::new(original).decode().unwrap();
+        let lenient = Decoder::new(&messy).decode().unwrap();
+        assert_eq!(lenient.pixels, expected.pixels);
+        assert!(matches!(
+            Decoder::new(&messy).strict(true).decode().unwrap_err().kind(),
+            DecodeError::InvalidMarker(0x00)
+        ));
+        assert!(matches!(
+            Decoder::new(&original[..original.len() - 2]).strict(true).decode().unwrap_err().kind(),
+            DecodeError::MissingEndOfImage
+        ));
+    }
+
+    #[test]
+    fn files_must_begin_with_soi() {
+        let png: &[u8] = &[0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+        for bytes in [png, &[], &[0xff], &[0xff, Markers::EOI]] {
+            assert!(matches!(Decoder::new(bytes).decode().unwrap_err().kind(), DecodeError::NotAJpeg));
+        }
+        assert!(matches!(Decoder::new(png).read_metadata_only().unwrap_err().kind(), DecodeError::NotAJpeg));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn readers_and_io_errors_pass_through_the_question_mark() {
+        let bytes: &[u8] = include_bytes!("images/gradient_420.jpg");
+        assert_eq!(decode_from_reader(bytes).unwrap().pixels, Decoder::new(bytes).decode().unwrap().pixels);
+
+        fn open_and_decode(path: &std::path::Path) -> Result<Image, DecodeError> {
+            let image = decode_from_reader(std::fs::File::open(path)?)?;
+            return Ok(image)
+        }
+        let error = open_and_decode(std::path::Path::new("src/images/missing.jpg")).unwrap_err();
+        assert!(matches!(&error, DecodeError::Io(io) if io.kind() == std::io::ErrorKind::NotFound));
+        assert!(std::error::Error::source(&error).is_some());
+        assert!(open_and_decode(std::path::Path::new("src/images/guy.jpg")).is_ok());
+    }
+
+    #[test]
+    fn fill_bytes_and_reserved_markers_between_segments() {
+        let original: &[u8] = include_bytes!("images/guy.jpg");
+        let dqt = original.windows(2).position(|m| m == [0xff, Markers::DQT]).unwrap();
+        let expected = Decoder::new(original).decode().unwrap();
+
+        // Any number of 0xff fill bytes may come before a marker
+        let mut filled: Vec<u8> = original[..dqt].to_vec();
+        filled.extend([0xff, 0xff, 0xff]);
+        filled.extend(&original[dqt..]);
+        assert_eq!(Decoder::new(&filled).strict(true).decode().unwrap().pixels, expected.pixels);
+
+        // A reserved marker is skipped along with the bytes after it
+        let mut reserved: Vec<u8> = original[..dqt].to_vec();
+        reserved.extend([0xff, 0x05, 0x00, 0x03, 0x42]);
+        reserved.extend(&original[dqt..]);
+        assert_eq!(Decoder::new(&reserved).decode().unwrap().pixels, expected.pixels);
+        assert!(matches!(
+            Decoder::new(&reserved).strict(true).decode().unwrap_err().kind(),
+            DecodeError::UnknownMarker(0x05)
+        ));
+    }
+
+    #[test]
+    fn stuffed_bytes_keep_only_the_0xff() {
+        // A scan header for one component followed by entropy coded data
+        // holding three stuffed bytes, two of them back to back
+        let mut bytes: Vec<u8> = vec![0xff, Markers::SOI, 0xff, Markers::SOS, 0x00, 0x08, 0x01, 0x01, 0x00, 0x00, 0x3f, 0x00];
+        bytes.extend([0x12, 0xff, 0x00, 0x34, 0xff, 0x00, 0xff, 0x00, 0x56]);
+        bytes.extend([0xff, Markers::EOI]);
+        let frame = read_frame(&bytes, &false, &DecodeOptions { strict: true, ..Default::default() }).unwrap();
+        let scan = &frame.scans[0];
+        assert_eq!(scan.entropy_coded_segments, vec![0x12, 0xff, 0x34, 0xff, 0xff, 0x56]);
+        assert_eq!(scan.stuffed_offsets, vec![1, 3, 4]);
+        assert_eq!(scan.file_offset(&5), 20);
+
+        // 0xff00 before any scan is skipped unless strict
+        let stray: Vec<u8> = vec![0xff, Markers::SOI, 0xff, 0x00, 0xff, Markers::EOI];
+        assert!(read_frame(&stray, &false, &DecodeOptions::default()).unwrap().scans.is_empty());
+        assert!(matches!(
+            read_frame(&stray, &false, &DecodeOptions { strict: true, ..Default::default() }).unwrap_err().kind(),
+            DecodeError::UnknownMarker(Markers::ESC)
+        ));
+    }
+
+    #[test]
+    fn errors_report_where_they_occurred() {
+        let original: &[u8] = include_bytes!("images/guy.jpg");
+        let dqt = original.windows(2).position(|m| m == [0xff, Markers::DQT]).unwrap();
+        let mut messy: Vec<u8> = original[..dqt].to_vec();
+        messy.push(0x00);
+        messy.extend(&original[dqt..]);
+        let error = Decoder::new(&messy).strict(true).decode().unwrap_err();
+        assert!(matches!(error, DecodeError::At { offset, scan: None, .. } if offset == dqt));
+        assert_eq!(error.to_string(), format!("expected a marker but found 0x00 at offset {:#X}", dqt));
+
+        // Three stuffed 0xff bytes make the first 16 bits of the scan all 1s,
+        // which no Huffman code matches
+        let sos = segments(original).find(|segment| segment.marker == Markers::SOS).unwrap();
+        let data_offset = sos.offset + 2 + sos.length as usize;
+        let mut corrupt: Vec<u8> = original[..data_offset].to_vec();
+        corrupt.extend([0xff, 0x00, 0xff, 0x00, 0xff, 0x00]);
+        corrupt.extend(&original[data_offset..]);
+        let error = Decoder::new(&corrupt).decode().unwrap_err();
+        assert!(matches!(error.kind(), DecodeError::InvalidHuffmanCode));
+        // The reader stopped after two bytes, and both were stuffed
+        assert_eq!(error.to_string(), format!("invalid Huffman code at offset {:#X} in scan 0", data_offset + 4));
+    }
+
+    #[test]
+    fn dqt_segment_with_mixed_precision_tables() {
+        // One 8-bit table (destination 0) and one 16-bit table (destination 1)
+        let mut dqt: Vec<u8> = vec![0x00];
+        dqt.extend(1..=64u8);
+        dqt.push(0x11);
+        for value in 1..=64u16 {
+            dqt.extend((value * 300).to_be_bytes());
+        }
+        let mut bytes: Vec<u8> = vec![0xff, Markers::SOI, 0xff, Markers::DQT];
+        bytes.extend((dqt.len() as u16 + 2).to_be_bytes());
+        bytes.extend(&dqt);
+        bytes.extend([0xff, Markers::EOI]);
+
+        let frame = read_frame(&bytes, &false, &DecodeOptions { strict: true, ..Default::default() }).unwrap();
+        let eight_bit = frame.quantization_tables[0].as_ref().unwrap();
+        let sixteen_bit = frame.quantization_tables[1].as_ref().unwrap();
+        assert_eq!((eight_bit.precision, sixteen_bit.precision), (0, 1));
+        // Elements stay in the zigzag order of the file
+        assert_eq!(eight_bit.elements[2], 3);
+        assert_eq!(sixteen_bit.elements[2], 900);
+        assert_eq!(sixteen_bit.elements[63], 64 * 300);
+
+        // A segment that ends after the first byte of its table, ahead of
+        // gradient_420.jpg's own segments
+        let original: &[u8] = include_bytes!("images/gradient_420.jpg");
+        let mut bytes: Vec<u8> = original[..2].to_vec();
+        bytes.extend([0xff, Markers::DQT, 0, 3, 0x02]);
+        bytes.extend(&original[2..]);
+        let frame = Decoder::new(&bytes).read_segments().unwrap();
+        assert!(frame.quantization_tables[2].is_none());
+        assert_eq!(Decoder::new(&bytes).decode().unwrap().pixels, Decoder::new(original).decode().unwrap().pixels);
+        assert!(matches!(
+            Decoder::new(&bytes).strict(true).read_segments().unwrap_err().kind(),
+            DecodeError::SegmentLengthMismatch(Markers::DQT)
+        ));
+    }
+
+    #[test]
+    fn dimensions_come_from_the_first_frame_header() {
+        assert_eq!(decode_dimensions(include_bytes!("images/gradient_420.jpg")).unwrap(), (61, 37));
+        assert_eq!(decode_dimensions(include_bytes!("images/gradient_420_arithmetic.jpg")).unwrap(), (61, 37));
+        let guy = Decoder::new(include_bytes!("images/guy.jpg")).read_metadata_only().unwrap();
+        assert_eq!(decode_dimensions(include_bytes!("images/guy.jpg")).unwrap(), (guy.width, guy.height));
+
+        // Everything after the frame header can be missing
+        let original: &[u8] = include_bytes!("images/gradient_420.jpg");
+        let sof = segments(original).find(|segment| segment.marker == Markers::SOF0).unwrap();
+        assert_eq!(decode_dimensions(&original[..sof.offset + 9]).unwrap(), (61, 37));
+        assert!(matches!(decode_dimensions(&original[..sof.offset + 8]), Err(DecodeError::SegmentLengthMismatch(Markers::SOF0))));
+
+        assert!(matches!(decode_dimensions(&original[..sof.offset]), Err(DecodeError::MissingFrameHeader)));
+        assert!(matches!(decode_dimensions(b"GIF89a"), Err(DecodeError::NotAJpeg)));
+    }
+
+    #[test]
+    fn segments_walks_markers_and_skips_scan_data() {
+        let bytes: &[u8] = include_bytes!("images/gradient_420_restart.jpg");
+        let all: Vec<Segment> = segments(bytes).collect();
+        assert_eq!(all.first().map(|segment| segment.marker), Some(Markers::SOI));
+        assert_eq!(all.last().map(|segment| (segment.marker, segment.offset)), Some((Markers::EOI, bytes.len() - 2)));
+        let restarts: Vec<u8> = all.iter()
+            .map(|segment| segment.marker)
+            .filter(|marker| (Markers::RST0..=Markers::RST7).contains(marker))
+            .collect();
+        assert_eq!(restarts, vec![Markers::RST0, Markers::RST1, Markers::RST2]);
+
+        let sof = all.iter().find(|segment| segment.marker == Markers::SOF0).unwrap();
+        assert_eq!(sof.length as usize, sof.payload.len() + 2);
+        assert_eq!(&bytes[sof.offset..sof.offset + 2], &[0xff, Markers::SOF0]);
+    }
+
+    #[test]
+    fn parser_resumes_across_chunks() {
+        let original: &[u8] = include_bytes!("images/gradient_420_restart.jpg");
+        let mut bytes: Vec<u8> = original.to_vec();
+        bytes.extend([0x12, 0x34]);
+        // Every marker segments finds is reported once its segment completes
+        let expected: Vec<Event> = segments(&bytes)
+            .map(|segment| match segment.marker {
+                Markers::EOI => Event::EndOfImage,
+                marker => Event::Segment { marker, offset: segment.offset }
+            })
+            .collect();
+        let options = DecodeOptions { strict: true, ..Default::default() };
+        let whole = read_frame(&bytes, &false, &options).unwrap();
+        for chunk_size in [1, 2, 7, 100, bytes.len()] {
+            let mut parser = Parser::new(&false, &options);
+            let mut events: Vec<Event> = Vec::new();
+            for chunk in bytes.chunks(chunk_size) {
+                events.extend(parser.feed(chunk).unwrap());
+            }
+            assert_eq!(events, expected);
+            assert_eq!(parser.frame().frame_header.total_horizontal_lines, 61);
+            let frame = parser.finish().unwrap();
+            assert_eq!(frame.scans[0].entropy_coded_segments, whole.scans[0].entropy_coded_segments);
+            assert_eq!(frame.scans[0].restart_offsets, whole.scans[0].restart_offsets);
+            assert_eq!(frame.trailer, vec![0x12, 0x34]);
+            assert_eq!(decode_frame(frame, &options).unwrap().pixels, Decoder::new(original).decode().unwrap().pixels);
+        }
+
+        // Headers are available as soon as their segments arrive
+        let sof = segments(original).find(|segment| segment.marker == Markers::SOF0).unwrap();
+        let sos = segments(original).find(|segment| segment.marker == Markers::SOS).unwrap();
+        let mut parser = Parser::new(&false, &options);
+        let events = parser.feed(&original[..sos.offset]).unwrap();
+        assert!(events.contains(&Event::Segment { marker: Markers::SOF0, offset: sof.offset }));
+        assert_eq!(parser.frame().frame_header.total_vertical_lines, 37);
+        assert!(matches!(parser.finish().unwrap_err().kind(), DecodeError::MissingEndOfImage));
+    }
+
+    #[test]
+    fn parsing_stops_at_eoi() {
+        let original: &[u8] = include_bytes!("images/guy.jpg");
+        let mut appended: Vec<u8> = original.to_vec();
+        // Garbage that would otherwise be an invalid marker
+        appended.extend([0x12, 0xff, 0x02, 0x00, 0xff]);
+        let options = DecodeOptions { strict: true, ..Default::default() };
+        let frame = read_frame(&appended, &false, &options).unwrap();
+        assert_eq!(frame.trailer, vec![0x12, 0xff, 0x02, 0x00, 0xff]);
+        assert_eq!(
+            Decoder::with_options(&appended, options).decode().unwrap().pixels,
+            Decoder::new(original).decode().unwrap().pixels
+        );
+    }
+
+    #[test]
+    fn metadata_segments_after_the_frame_header_and_scan() {
+        // gradient_420.jpg with a comment and an APP15 segment between SOF0
+        // and SOS, and a comment and an empty comment after the scan
+        let bytes: &[u8] = include_bytes!("images/gradient_420_comments.jpg");
+        let options = DecodeOptions { strict: true, ..Default::default() };
+        let frame = Decoder::with_options(bytes, options).read_segments().unwrap();
+        assert_eq!(frame.comments_text(), vec!["between SOF and SOS", "trailing comment", ""]);
+        assert_eq!(frame.application_data.last().map(|app| (app.marker, app.identifier())), Some((Markers::APP15, Some("Meta"))));
+        assert!(frame.trailer.is_empty());
+        assert_eq!(
+            Decoder::with_options(bytes, options).decode().unwrap().pixels,
+            Decoder::new(include_bytes!("images/gradient_420.jpg")).decode().unwrap().pixels
+        );
+
+        // A length too short to count itself
+        let mut short: Vec<u8> = bytes.to_vec();
+        let empty = short.len() - 6;
+        short[empty + 3] = 1;
+        assert!(matches!(Decoder::new(&short).read_segments().unwrap_err().kind(), DecodeError::SegmentLengthMismatch(Markers::COM)));
+    }
+
+    #[test]
+    fn overlong_segments_end_at_the_next_marker() {
+        // The comment between SOF0 and SOS is followed by an APP15 segment.
+        // Lengthening it makes it run into that segment, by just the 0xff or
+        // by part of the APP15 header.
+        let bytes: &[u8] = include_bytes!("images/gradient_420_comments.jpg");
+        let com = segments(bytes).find(|segment| segment.marker == Markers::COM).unwrap();
+        let expected = Decoder::new(bytes).read_segments().unwrap();
+        let pixels = Decoder::new(bytes).decode().unwrap().pixels;
+        for extra in [1, 5] {
+            let mut overlong: Vec<u8> = bytes.to_vec();
+            overlong[com.offset + 3] += extra;
+            let frame = Decoder::new(&overlong).read_segments().unwrap();
+            assert_eq!(frame.comments_text(), expected.comments_text());
+            assert_eq!(frame.application_data.last().unwrap().application_data, expected.application_data.last().unwrap().application_data);
+            assert_eq!(Decoder::new(&overlong).decode().unwrap().pixels, pixels);
+            assert!(matches!(Decoder::new(&overlong).strict(true).decode().unwrap_err().kind(), DecodeError::InvalidMarker(_)));
+        }
+
+        // An Exif segment holding a thumbnail, itself a JPEG with its own
+        // tables and frame, right after SOI. Only the marker it ran into is
+        // read again, not those of the thumbnail.
+        let original: &[u8] = include_bytes!("images/gradient_420.jpg");
+        let mut exif: Vec<u8> = b"Exif\0\0".to_vec();
+        exif.extend(include_bytes!("images/gray_22.jpg"));
+        let expected = Decoder::new(original).decode().unwrap();
+        for extra in [1, 4] {
+            let mut overlong: Vec<u8> = original[..2].to_vec();
+            overlong.extend([0xff, Markers::APP1]);
+            overlong.extend((exif.len() as u16 + 2 + extra).to_be_bytes());
+            overlong.extend(&exif);
+            overlong.extend(&original[2..]);
+            let frame = Decoder::new(&overlong).read_segments().unwrap();
+            assert_eq!(frame.application_data[0].application_data, exif);
+            assert_eq!(frame.application_data[1].identifier(), Some("JFIF"));
+            let image = Decoder::new(&overlong).decode().unwrap();
+            assert_eq!((image.width, image.height), (expected.width, expected.height));
+            assert_eq!(image.pixels, expected.pixels);
+        }
+    }
+
+    #[test]
+    fn fixed_size_segments_check_their_length() {
+        // gradient_420.jpg with a segment inserted before SOS
+        let original: &[u8] = include_bytes!("images/gradient_420.jpg");
+        let sos = segments(original).find(|segment| segment.marker == Markers::SOS).unwrap();
+        let with_segment = |marker: u8, data: &[u8]| -> Vec<u8> {
+            let mut bytes: Vec<u8> = original[..sos.offset].to_vec();
+            bytes.extend([0xff, marker]);
+            bytes.extend((data.len() as u16 + 2).to_be_bytes());
+            bytes.extend(data);
+            bytes.extend(&original[sos.offset..]);
+            return bytes
+        };
+        let strict = DecodeOptions { strict: true, ..Default::default() };
+
+        // Too short to hold the parameters
+        for (marker, data) in [(Markers::DRI, &[0][..]), (Markers::DNL, &[0][..])] {
+            for options in [strict, DecodeOptions::default()] {
+                assert!(matches!(
+                    Decoder::with_options(&with_segment(marker, data), options).read_segments().unwrap_err().kind(),
+                    DecodeError::SegmentLengthMismatch(found) if *found == marker
+                ));
+            }
+        }
+
+        // Bytes past the parameters are discarded in lenient mode
+        for (marker, data) in [(Markers::DRI, &[0, 0, 0, 0][..]), (Markers::EXP, &[0x11, 0][..])] {
+            let bytes = with_segment(marker, data);
+            assert!(matches!(
+                Decoder::with_options(&bytes, strict).read_segments().unwrap_err().kind(),
+                DecodeError::SegmentLengthMismatch(found) if *found == marker
+            ));
+            let frame = Decoder::new(&bytes).read_segments().unwrap();
+            assert_eq!(frame.restart_interval.map(|dri| (dri.length, dri.interval)), (marker == Markers::DRI).then_some((2, 0)));
+            assert_eq!(frame.expand_reference.map(|exp| exp.expand_horizontally), (marker == Markers::EXP).then_some(1));
+        }
+
+        // The other builders report data that disagrees with its length,
+        // including tables whose data disagrees with their own parameters
+        let data: Vec<u8> = vec![0x00, 1, 2];
+        assert!(matches!(QuantizationTable::default().build(&4, &data), Err(DecodeError::SegmentLengthMismatch(Markers::DQT))));
+        assert!(matches!(QuantizationTable::default().build(&3, &data), Err(DecodeError::SegmentLengthMismatch(Markers::DQT))));
+        assert!(matches!(HuffmanTable::default().build(&3, &data), Err(DecodeError::SegmentLengthMismatch(Markers::DHT))));
+        let mut dht: Vec<u8> = vec![0x00, 1];
+        dht.extend([0; 15]);
+        assert!(matches!(HuffmanTable::default().build(&17, &dht), Err(DecodeError::SegmentLengthMismatch(Markers::DHT))));
+        assert!(matches!(Comment::default().build(&4, &data), Err(DecodeError::SegmentLengthMismatch(Markers::COM))));
+        assert!(matches!(
+            ApplicationData::default().build(&Markers::APP1, &2, &data),
+            Err(DecodeError::SegmentLengthMismatch(Markers::APP1))
+        ));
+    }
+
+    #[test]
+    fn dnl_sets_or_corrects_the_frame_height() {
+        // gradient_420.jpg is 37 lines high. Its SOF height is replaced and
+        // a DNL segment inserted after the scan.
+        let original: &[u8] = include_bytes!("images/gradient_420.jpg");
+        let sof = segments(original).find(|segment| segment.marker == Markers::SOF0).unwrap();
+        let with_dnl = |frame_height: u16, lines: u16| -> Vec<u8> {
+            let mut bytes: Vec<u8> = original.to_vec();
+            bytes[sof.offset + 5..sof.offset + 7].copy_from_slice(&frame_height.to_be_bytes());
+            let eoi = bytes.len() - 2;
+            bytes.splice(eoi..eoi, [0xff, Markers::DNL, 0, 4, (lines >> 8) as u8, lines as u8]);
+            return bytes
+        };
+        let expected = Decoder::new(original).decode().unwrap();
+        let strict = DecodeOptions { strict: true, ..Default::default() };
+
+        // A height of 0 waits for the DNL, as the spec intends
+        for options in [strict, DecodeOptions::default()] {
+            let image = Decoder::with_options(&with_dnl(0, 37), options).decode().unwrap();
+            assert_eq!((image.width, image.height), (61, 37));
+            assert_eq!(image.pixels, expected.pixels);
+            assert!(Decoder::with_options(&with_dnl(37, 37), options).decode().is_ok());
+        }
+
+        // A placeholder height is corrected by the DNL unless strict
+        let placeholder = with_dnl(48, 37);
+        assert!(matches!(
+            Decoder::with_options(&placeholder, strict).decode().unwrap_err().kind(),
+            DecodeError::NumberOfLinesMismatch { frame: 48, lines: 37 }
+        ));
+        let frame = Decoder::new(&placeholder).read_segments().unwrap();
+        assert_eq!((frame.frame_header.total_vertical_lines, frame.lines.map(|lines| lines.total_lines)), (37, Some(37)));
+        assert_eq!(Decoder::new(&placeholder).decode().unwrap().pixels, expected.pixels);
+
+        // A DNL of 0 is invalid and is otherwise ignored
+        assert!(Decoder::with_options(&with_dnl(37, 0), strict).decode().is_err());
+        assert_eq!(Decoder::new(&with_dnl(37, 0)).decode().unwrap().pixels, expected.pixels);
+
+        // A DNL too short to hold NL
+        let mut short: Vec<u8> = with_dnl(0, 37);
+        let dnl = short.len() - 8;
+        short.splice(dnl + 2..dnl + 6, [0, 3, 0]);
+        assert!(matches!(
+            Decoder::new(&short).read_segments().unwrap_err().kind(),
+            DecodeError::SegmentLengthMismatch(Markers::DNL)
+        ));
+    }
+
+    #[test]
+    fn default_tables_fill_in_missing_huffman_tables() {
+        // Motion JPEG style frame: the same image with its DHT segments removed
+        let original: &[u8] = include_bytes!("images/gradient_420.jpg");
+        let mut abbreviated: Vec<u8> = Vec::new();
+        let mut copied_up_to = 0;
+        for segment in segments(original).filter(|segment| segment.marker == Markers::DHT) {
+            abbreviated.extend(&original[copied_up_to..segment.offset]);
+            copied_up_to = segment.offset + 2 + segment.length as usize;
+        }
+        abbreviated.extend(&original[copied_up_to..]);
+        assert!(abbreviated.len() < original.len());
+
+        assert!(matches!(
+            Decoder::new(&abbreviated).decode(),
+            Err(DecodeError::MissingHuffmanTable { class: 0, destination_id: 0 })
+        ));
+        let options = DecodeOptions { default_tables: true, ..Default::default() };
+        assert_eq!(
+            Decoder::with_options(&abbreviated, options).decode().unwrap().pixels,
+            Decoder::new(original).decode().unwrap().pixels
+        );
+    }
+
+    #[test]
+    fn quantization_tables_are_fixed_by_a_components_first_scan() {
+        // scans_420_separate.jpg codes each component in its own scan. A DQT
+        // segment after the luma scan redefines both tables with all ones,
+        // which only the chroma components pick up.
+        let original: &[u8] = include_bytes!("images/scans_420_separate.jpg");
+        let second_scan = segments(original).filter(|segment| segment.marker == Markers::SOS).nth(1).unwrap();
+        let mut redefined: Vec<u8> = original[..second_scan.offset].to_vec();
+        redefined.extend([0xff, Markers::DQT, 0x00, 2 + 2 * 65]);
+        for destination_id in [0, 1] {
+            redefined.push(destination_id);
+            redefined.extend([1; 64]);
+        }
+        redefined.extend(&original[second_scan.offset..]);
+
+        let frame = Decoder::new(&redefined).read_segments().unwrap();
+        let components = &frame.frame_header.components;
+        let expected = Decoder::new(original).read_segments().unwrap();
+        assert_eq!(frame.quantization_table(&components[0]).unwrap().elements, expected.quantization_table(&components[0]).unwrap().elements);
+        assert_eq!(frame.quantization_table(&components[1]).unwrap().elements, [1; 64]);
+        assert_eq!(
+            Decoder::new(&redefined).decode_component(&components[0].id).unwrap().samples,
+            Decoder::new(original).decode_component(&components[0].id).unwrap().samples
+        );
+
+        // Pq is 0 or 1 and Tq at most 3
+        let dqt = segments(original).find(|segment| segment.marker == Markers::DQT).unwrap();
+        for byte in [0x04, 0x20] {
+            let mut invalid: Vec<u8> = original.to_vec();
+            invalid[dqt.offset + 4] = byte;
+            assert!(matches!(
+                Decoder::new(&invalid).read_segments().unwrap_err().kind(),
+                DecodeError::InvalidQuantizationTable
+            ));
+        }
+    }
+
+    #[test]
+    fn missing_quantization_tables_are_reported() {
+        let original: &[u8] = include_bytes!("images/gradient_420.jpg");
+        let mut abbreviated: Vec<u8> = Vec::new();
+        let mut copied_up_to = 0;
+        for segment in segments(original).filter(|segment| segment.marker == Markers::DQT) {
+            abbreviated.extend(&original[copied_up_to..segment.offset]);
+            copied_up_to = segment.offset + 2 + segment.length as usize;
+        }
+        abbreviated.extend(&original[copied_up_to..]);
+        assert!(matches!(
+            Decoder::new(&abbreviated).decode(),
+            Err(DecodeError::MissingQuantizationTable { selector: 0 })
+        ));
+
+        // Tq only goes up to 3
+        let sof = segments(original).find(|segment| segment.marker == Markers::SOF0).unwrap();
+        let mut out_of_range: Vec<u8> = original.to_vec();
+        out_of_range[sof.offset + 12] = 9;
+        assert!(matches!(
+            Decoder::new(&out_of_range).decode(),
+            Err(DecodeError::MissingQuantizationTable { selector: 9 })
+        ));
+    }
+
+    #[test]
+    fn scan_components_are_matched_by_id() {
+        // Renumber the components from 1, 2, 3 to 0, 7, 3 in both the frame
+        // header and the scan header
+        let original: &[u8] = include_bytes!("images/gradient_420.jpg");
+        let renumber = |bytes: &mut Vec<u8>, ids: [u8; 3]| {
+            for segment in segments(original) {
+                let components_start = match segment.marker {
+                    Markers::SOF0 => segment.offset + 10,
+                    Markers::SOS => segment.offset + 5,
+                    _ => continue
+                };
+                let stride = if segment.marker == Markers::SOS { 2 } else { 3 };
+                for (idx, id) in ids.iter().enumerate() {
+                    bytes[components_start + idx * stride] = *id;
+                }
+            }
+        };
+        let mut renumbered: Vec<u8> = original.to_vec();
+        renumber(&mut renumbered, [0, 7, 3]);
+        assert_eq!(
+            Decoder::new(&renumbered).decode().unwrap().pixels,
+            Decoder::new(original).decode().unwrap().pixels
+        );
+
+        // A scan component that matches no frame component
+        let mut mismatched: Vec<u8> = original.to_vec();
+        let sos = segments(original).find(|segment| segment.marker == Markers::SOS).unwrap();
+        mismatched[sos.offset + 5] = 9;
+        assert!(matches!(
+            Decoder::new(&mismatched).decode(),
+            Err(DecodeError::UnknownScanComponent(9))
+        ));
+    }
+
+    #[test]
+    fn component_ids_may_start_at_0() {
+        // The same encoding as gradient_420.jpg with components numbered 0, 1
+        // and 2 instead of 1, 2 and 3
+        let zero_based: &[u8] = include_bytes!("images/gradient_420_zero_ids.jpg");
+        let one_based: &[u8] = include_bytes!("images/gradient_420.jpg");
+        let info = Decoder::new(zero_based).read_segments().unwrap().component_info();
+        assert_eq!(info.iter().map(|component| component.id).collect::<Vec<u8>>(), vec![0, 1, 2]);
+        assert_eq!(Decoder::new(zero_based).decode().unwrap().pixels, Decoder::new(one_based).decode().unwrap().pixels);
+        let planes = Decoder::new(zero_based).decode_to_planar_yuv().unwrap();
+        let one_based_planes = Decoder::new(one_based).decode_to_planar_yuv().unwrap();
+        for (plane, one_based_plane) in planes.iter().zip(one_based_planes.iter()) {
+            assert_eq!(plane.samples, one_based_plane.samples);
+        }
+        assert_eq!(Decoder::new(zero_based).decode_component(&0).unwrap().samples, planes[0].samples);
+        let blocks: Vec<(u8, usize, usize, [i16; 64])> = Decoder::new(zero_based).blocks().unwrap().collect();
+        assert_eq!((blocks[0].0, blocks[40].0, blocks[52].0), (0, 1, 2));
+        assert_eq!(decode_preview(zero_based).unwrap().pixels, decode_preview(one_based).unwrap().pixels);
+    }
+
+    #[test]
+    fn huffman_tables_are_looked_up_by_destination_id() {
+        // Move the tables from destinations 0 and 1 to 3 and 2, as an
+        // extended sequential file may, and point the scan at them
+        let original: &[u8] = include_bytes!("images/gradient_420.jpg");
+        let move_destination = |byte: u8| match byte & 0x0f {
+            0 => (byte & 0xf0) | 3,
+            _ => (byte & 0xf0) | 2
+        };
+        let mut moved: Vec<u8> = original.to_vec();
+        for segment in segments(original) {
+            match segment.marker {
+                Markers::SOF0 => moved[segment.offset + 1] = Markers::SOF1,
+                Markers::DHT => {
+                    let mut table_start = segment.offset + 4;
+                    while table_start < segment.offset + 2 + segment.length as usize {
+                        moved[table_start] = move_destination(original[table_start]);
+                        let total_codes: usize = original[table_start + 1..table_start + 17].iter().map(|count| *count as usize).sum();
+                        table_start += 17 + total_codes;
+                    }
+                },
+                Markers::SOS => {
+                    for idx in 0..3 {
+                        let selectors = original[segment.offset + 6 + idx * 2];
+                        moved[segment.offset + 6 + idx * 2] = (move_destination(selectors >> 4) << 4) | move_destination(selectors & 0x0f);
+                    }
+                },
+                _ => {}
+            }
+        }
+        assert_eq!(
+            Decoder::new(&moved).decode().unwrap().pixels,
+            Decoder::new(original).decode().unwrap().pixels
+        );
+
+        // Destinations only go up to 3
+        let dht = segments(original).find(|segment| segment.marker == Markers::DHT).unwrap();
+        let mut out_of_range: Vec<u8> = original.to_vec();
+        out_of_range[dht.offset + 4] = 0x04;
+        assert!(matches!(Decoder::new(&out_of_range).decode().unwrap_err().kind(), DecodeError::InvalidHuffmanTable));
+        // and classes are only DC (0) or AC (1), so class 5 isn't read as AC
+        for byte in [0x50, 0x21] {
+            out_of_range[dht.offset + 4] = byte;
+            assert!(matches!(Decoder::new(&out_of_range).read_segments().unwrap_err().kind(), DecodeError::InvalidHuffmanTable));
+        }
+    }
+
+    #[test]
+    fn parameter_bytes_split_into_nibbles() {
+        let mut component = FrameComponent::default();
+        component.sample_factor(&0x21);
+        assert_eq!((component.horizontal_sample_factor, component.vertical_sample_factor), (2, 1));
+        component.sample_factor(&0x1f);
+        assert_eq!((component.horizontal_sample_factor, component.vertical_sample_factor), (1, 15));
+        let mut scan_header = ScanHeader::default();
+        scan_header.successive_approximation(&0xd2);
+        assert_eq!((scan_header.successive_approximation_hi, scan_header.successive_approximation_lo), (13, 2));
+        let mut quantization_table = QuantizationTable::default();
+        quantization_table.precision_and_destination_id(&0x13);
+        assert_eq!((quantization_table.precision, quantization_table.destination_id), (1, 3));
+        let mut huffman_table = HuffmanTable::default();
+        huffman_table.class_and_destination_id(&0x10);
+        assert_eq!((huffman_table.class, huffman_table.destination_id), (1, 0));
+        let mut arithmetic_table = ArithmeticTable::default();
+        arithmetic_table.class_and_destination_id(&0x0f);
+        assert_eq!((arithmetic_table.class, arithmetic_table.destination_id), (0, 15));
+        let mut expand = ExpandReference::default();
+        expand.expand_horizontally_and_vertically(&0x10);
+        assert_eq!((expand.expand_horizontally, expand.expand_vertically), (1, 0));
+    }
+
+    #[test]
+    fn scan_header_parameters_follow_the_components() {
+        // Two components, then Ss = 1, Se = 5, Ah = 2, Al = 1 as in an AC
+        // refinement scan of a progressive file
+        let data: Vec<u8> = vec![2, 1, 0x00, 2, 0x11, 1, 5, 0x21];
+        let mut scan_header = ScanHeader::default();
+        scan_header.build(&(data.len() as u16), &data).unwrap();
+        assert_eq!(scan_header.components.len(), 2);
+        assert_eq!((scan_header.components[1].id, scan_header.components[1].ac_entropy_table_dest), (2, 1));
+        assert_eq!(scan_header.spectral_selection_start, 1);
+        assert_eq!(scan_header.spectral_selection_end, 5);
+        assert_eq!(scan_header.successive_approximation_hi, 2);
+        assert_eq!(scan_header.successive_approximation_lo, 1);
+
+        // Ns disagrees with the length, including an Ns whose components
+        // would take up more than 255 bytes, or is outside 1 to 4
+        let five: Vec<u8> = [vec![5], [1, 0x00].repeat(5), vec![0, 63, 0]].concat();
+        for data in [vec![3, 1, 0x00, 2, 0x11, 1, 5, 0x21], vec![200, 1, 0x00, 0, 63, 0], vec![], vec![0, 0, 63, 0], five] {
+            assert!(matches!(
+                ScanHeader::default().build(&(data.len() as u16), &data),
+                Err(DecodeError::SegmentLengthMismatch(Markers::SOS))
+            ));
+        }
+        // A scan without components is rejected before it is decoded
+        let jpeg = flat_jpeg(&[0], &[&[]]);
+        let options = DecodeOptions { default_tables: true, ..Default::default() };
+        assert!(matches!(
+            Decoder::with_options(&jpeg, options).decode().unwrap_err().kind(),
+            DecodeError::SegmentLengthMismatch(Markers::SOS)
+        ));
+    }
+
+    #[test]
+    fn frame_header_parses_each_component_once() {
+        // 8-bit, 37x61, three components with 2x2 luma and 1x1 chroma
+        let data: Vec<u8> = vec![8, 0, 37, 0, 61, 3, 1, 0x22, 0, 2, 0x11, 1, 3, 0x11, 1];
+        let mut frame_header = FrameHeader::default();
+        frame_header.build(&(data.len() as u16), &Markers::SOF0, &data).unwrap();
+        assert_eq!(frame_header.components.len(), frame_header.total_components as usize);
+        let ids: Vec<u8> = frame_header.components.iter().map(|component| component.id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+        assert_eq!(frame_header.components[0].horizontal_sample_factor, 2);
+        let frame = read_frame(include_bytes!("images/guy.jpg"), &true, &DecodeOptions::default()).unwrap();
+        assert_eq!(frame.frame_header.components.len(), frame.frame_header.total_components as usize);
+
+        // Nf disagrees with the length, or the length is too short for the
+        // frame parameters
+        for data in [vec![8, 0, 37, 0, 61, 1], vec![8]] {
+            assert!(matches!(
+                FrameHeader::default().build(&(data.len() as u16), &Markers::SOF1, &data),
+                Err(DecodeError::SegmentLengthMismatch(Markers::SOF1))
+            ));
+        }
+        // The same through the parser, for an SOF segment whose length is 3
+        let original: &[u8] = include_bytes!("images/guy.jpg");
+        let sof = segments(original).find(|segment| segment.marker == Markers::SOF0).unwrap();
+        let mut short: Vec<u8> = original[..sof.offset].to_vec();
+        short.extend([0xff, Markers::SOF0, 0, 3, 8]);
+        short.extend(&original[sof.offset + 2 + sof.length as usize..]);
+        assert!(matches!(
+            Decoder::new(&short).read_segments().unwrap_err().kind(),
+            DecodeError::SegmentLengthMismatch(Markers::SOF0)
+        ));
+    }
+
+    #[test]
+    fn oversized_frames_are_rejected() {
+        // Claim 65535x65535 in the SOF segment of a small file
+        let original: &[u8] = include_bytes!("images/guy.jpg");
+        let sof = segments(original).find(|segment| segment.marker == Markers::SOF0).unwrap();
+        let mut bomb: Vec<u8> = original.to_vec();
+        bomb[sof.offset + 5..sof.offset + 9].copy_from_slice(&[0xff; 4]);
+        assert!(matches!(
+            Decoder::new(&bomb).decode().unwrap_err().kind(),
+            DecodeError::DimensionsTooLarge { width: 65535, height: 65535 }
+        ));
+
+        let options = DecodeOptions { max_width: Some(100), ..Default::default() };
+        assert!(matches!(
+            Decoder::with_options(original, options).read_metadata_only().unwrap_err().kind(),
+            DecodeError::DimensionsTooLarge { width: 215, height: 154 }
+        ));
+        let options = DecodeOptions { max_pixels: 215 * 154, max_height: Some(154), ..Default::default() };
+        assert!(Decoder::with_options(original, options).decode().is_ok());
+    }
+
+    #[test]
+    fn sequential_scans_must_cover_all_coefficients() {
+        let original: &[u8] = include_bytes!("images/guy.jpg");
+        let sos = segments(original).find(|segment| segment.marker == Markers::SOS).unwrap();
+        // Se is the second to last byte of the scan header
+        let se_idx = sos.offset + 2 + sos.length as usize - 2;
+        assert_eq!(original[se_idx], 63);
+        let mut bytes: Vec<u8> = original.to_vec();
+        bytes[se_idx] = 5;
+        assert!(matches!(
+            Decoder::new(&bytes).decode(),
+            Err(DecodeError::InvalidScanParameters { spectral_selection_start: 0, spectral_selection_end: 5, .. })
+        ));
+    }
+
+    #[test]
+    fn component_info_gives_each_plane_size() {
+        // 61x37 with 2x2 luma, so chroma covers half of each, rounded up
+        let frame = Decoder::new(include_bytes!("images/gradient_420.jpg")).read_segments().unwrap();
+        let info = frame.component_info();
+        assert_eq!(info.iter().map(|component| (component.id, component.width, component.height)).collect::<Vec<_>>(), vec![(1, 61, 37), (2, 31, 19), (3, 31, 19)]);
+        assert_eq!((info[0].horizontal_sample_factor, info[0].vertical_sample_factor, info[0].quantization_table_selector), (2, 2, 0));
+        assert_eq!((info[1].horizontal_sample_factor, info[1].vertical_sample_factor, info[1].quantization_table_selector), (1, 1, 1));
+
+        // Planes come out at the sizes given
+        let planes = Decoder::new(include_bytes!("images/gradient_422.jpg")).decode_to_planar_yuv().unwrap();
+        let frame = Decoder::new(include_bytes!("images/gradient_422.jpg")).read_segments().unwrap();
+        for (plane, component) in planes.iter().zip(frame.component_info()) {
+            assert_eq!((plane.width, plane.height), (component.width, component.height));
+        }
+        assert_eq!((planes[1].width, planes[1].height), (31, 37));
+    }
+
+    #[test]
+    fn coding_process_follows_the_sof_marker() {
+        let original: &[u8] = include_bytes!("images/guy.jpg");
+        let sof = segments(original).find(|segment| segment.marker == Markers::SOF0).unwrap();
+        let mut bytes: Vec<u8> = original.to_vec();
+        for (marker, process, progressive) in [
+            (Markers::SOF0, CodingProcess::Baseline, false),
+            (Markers::SOF1, CodingProcess::ExtendedSequential, false),
+            (Markers::SOF2, CodingProcess::Progressive, true),
+            (Markers::SOF3, CodingProcess::Lossless, false),
+            (Markers::SOF6, CodingProcess::Differential, true),
+            (Markers::SOF9, CodingProcess::Arithmetic, false),
+            (Markers::SOF10, CodingProcess::Arithmetic, true)
+        ] {
+            bytes[sof.offset + 1] = marker;
+            let frame = read_frame(&bytes, &true, &DecodeOptions::default()).unwrap();
+            assert_eq!((frame.coding_process(), frame.is_progressive()), (Some(process), progressive));
+        }
+        assert_eq!(Frame::default().coding_process(), None);
+        assert!(!Frame::default().is_progressive());
+    }
+
+    #[test]
+    fn sampling_summary_classifies_subsampling() {
+        let frame_with = |factors: &[(u8, u8)]| {
+            let mut frame = Frame::default();
+            for (idx, (h, v)) in factors.iter().enumerate() {
+                frame.frame_header.components.push(FrameComponent {
+                    id: idx as u8 + 1,
+                    horizontal_sample_factor: *h,
+                    vertical_sample_factor: *v,
+                    quantization_table_selector: 0
+                });
+            }
+            frame
+        };
+        assert_eq!(frame_with(&[(1, 1)]).sampling_summary(), None);
+        assert_eq!(frame_with(&[(1, 1), (1, 1), (1, 1)]).sampling_summary(), Some(ChromaSubsampling::Y444));
+        assert_eq!(frame_with(&[(2, 2), (2, 2), (2, 2)]).sampling_summary(), Some(ChromaSubsampling::Y444));
+        assert_eq!(frame_with(&[(2, 1), (1, 1), (1, 1)]).sampling_summary(), Some(ChromaSubsampling::Y422));
+        assert_eq!(frame_with(&[(2, 2), (1, 1), (1, 1)]).sampling_summary(), Some(ChromaSubsampling::Y420));
+        assert_eq!(frame_with(&[(1, 2), (1, 1), (1, 1)]).sampling_summary(), Some(ChromaSubsampling::Y440));
+        assert_eq!(frame_with(&[(4, 1), (1, 1), (1, 1)]).sampling_summary(), Some(ChromaSubsampling::Y411));
+        assert_eq!(frame_with(&[(2, 2), (2, 1), (1, 1)]).sampling_summary(), Some(ChromaSubsampling::Other));
+        assert_eq!(frame_with(&[(3, 1), (2, 1), (2, 1)]).sampling_summary(), Some(ChromaSubsampling::Other));
+    }
+
+    #[test]
+    fn quality_is_estimated_from_the_luminance_table() {
+        let base = annex_k::quantization_table(&0).unwrap();
+        for quality in [1u32, 5, 25, 50, 75, 90, 95, 98, 100] {
+            // Table scaling as done by libjpeg's jpeg_quality_scaling
+            let scale = if quality < 50 { 5000 / quality } else { 200 - quality * 2 };
+            let mut table = annex_k::quantization_table(&0).unwrap();
+            for (element, base_element) in table.elements.iter_mut().zip(base.elements.iter()) {
+                *element = ((u32::from(*base_element) * scale + 50) / 100).clamp(1, 255) as u16;
+            }
+            let mut frame = Frame::default();
+            frame.quantization_tables[0] = Some(table);
+            let estimate = frame.estimated_quality().unwrap();
+            assert!(estimate.abs_diff(quality as u8) <= 1, "quality {} estimated as {}", quality, estimate);
+        }
+        assert_eq!(Frame::default().estimated_quality(), None);
+    }
+
+    #[test]
+    fn adobe_app14_transform_flag() {
+        let segment = |marker: u8, data: &[u8]| ApplicationData {
+            marker,
+            length: data.len() as u16,
+            application_data: data.to_vec(),
+            ..Default::default()
+        };
+        let adobe = |transform: u8| {
+            let mut data = b"Adobe\x00\x64\x00\x00\x00\x00".to_vec();
+            data.push(transform);
+            data
+        };
+        assert_eq!(segment(Markers::APP14, &adobe(0)).adobe_color_transform(), Some(ColorTransform::Unknown));
+        assert_eq!(segment(Markers::APP14, &adobe(1)).adobe_color_transform(), Some(ColorTransform::YCbCr));
+        assert_eq!(segment(Markers::APP14, &adobe(2)).adobe_color_transform(), Some(ColorTransform::Ycck));
+        // Other application segments and truncated data are ignored
+        assert_eq!(segment(Markers::APP13, &adobe(0)).adobe_color_transform(), None);
+        assert_eq!(segment(Markers::APP14, b"Adobe").adobe_color_transform(), None);
+    }
+
+    #[test]
+    fn jfxx_thumbnails_in_each_format() {
+        let segment = |extension_code: u8, thumbnail: &[u8]| {
+            let mut data = b"JFXX\0".to_vec();
+            data.push(extension_code);
+            data.extend(thumbnail);
+            ApplicationData { marker: Markers::APP0, length: data.len() as u16, application_data: data, ..Default::default() }
+        };
+        let options = DecodeOptions::default();
+
+        let jpeg: &[u8] = include_bytes!("images/gradient_422.jpg");
+        let decoded = segment(0x10, jpeg).jfxx_thumbnail(&options).unwrap().unwrap();
+        assert_eq!(decoded.pixels, Decoder::new(jpeg).decode().unwrap().pixels);
+
+        let mut palettized: Vec<u8> = vec![2, 1];
+        let mut palette: Vec<u8> = vec![0; 768];
+        palette[3..6].copy_from_slice(&[10, 20, 30]);
+        palette[765..].copy_from_slice(&[40, 50, 60]);
+        palettized.extend(palette);
+        palettized.extend([255, 1]);
+        let thumbnail = segment(0x11, &palettized).jfxx_thumbnail(&options).unwrap().unwrap();
+        assert_eq!((thumbnail.width, thumbnail.height), (2, 1));
+        assert_eq!(thumbnail.pixels, vec![40, 50, 60, 10, 20, 30]);
+
+        let rgb: Vec<u8> = vec![1, 2, 7, 8, 9, 4, 5, 6];
+        let bgr = DecodeOptions { output: Some(PixelFormat::Bgr8), ..Default::default() };
+        let thumbnail = segment(0x13, &rgb).jfxx_thumbnail(&bgr).unwrap().unwrap();
+        assert_eq!((thumbnail.width, thumbnail.height), (1, 2));
+        assert_eq!(thumbnail.pixels, vec![9, 8, 7, 6, 5, 4]);
+
+        assert!(matches!(segment(0x13, &rgb[..5]).jfxx_thumbnail(&options), Err(DecodeError::SegmentLengthMismatch(Markers::APP0))));
+        let jfif = ApplicationData { marker: Markers::APP0, length: 5, application_data: b"JFIF\0".to_vec(), ..Default::default() };
+        assert!(jfif.jfxx_thumbnail(&options).unwrap().is_none());
+    }
+
+    #[test]
+    fn application_segment_identifiers() {
+        assert_eq!(application_identifier(b"JFIF\x00\x01\x02"), Some("JFIF"));
+        assert_eq!(application_identifier(b"Exif\x00\x00MM"), Some("Exif"));
+        assert_eq!(application_identifier(b"Adobe\x00\x64\x00\x00\x00\x00\x01"), Some("Adobe"));
+        // Binary data and unterminated text don't name an application
+        assert_eq!(application_identifier(b"\x00JFIF"), None);
+        assert_eq!(application_identifier(b"\x01\x02\x00"), None);
+        assert_eq!(application_identifier(b"JFIF"), None);
+    }
+
+    #[test]
+    fn mpf_images_locate_each_embedded_image() {
+        // An APP2 segment with an MP Index IFD of version, image count and
+        // entry tags, followed by the entries, in either byte order
+        let mpf_segment = |big_endian: bool, images: &[(u32, u32)]| -> Vec<u8> {
+            let u16_bytes = |value: u16| if big_endian { value.to_be_bytes() } else { value.to_le_bytes() };
+            let u32_bytes = |value: u32| if big_endian { value.to_be_bytes() } else { value.to_le_bytes() };
+            let mut header: Vec<u8> = if big_endian { b"MM\0*".to_vec() } else { b"II*\0".to_vec() };
+            header.extend(u32_bytes(8));
+            header.extend(u16_bytes(3));
+            for (tag, kind, count, value) in [(0xb000, 7, 4, u32::from_be_bytes(*b"0100")), (0xb001, 4, 1, images.len() as u32), (0xb002, 7, 16 * images.len() as u32, 50)] {
+                header.extend(u16_bytes(tag));
+                header.extend(u16_bytes(kind));
+                header.extend(u32_bytes(count));
+                header.extend(u32_bytes(value));
+            }
+            header.extend(u32_bytes(0));
+            for (size, offset) in images.iter() {
+                header.extend(u32_bytes(0));
+                header.extend(u32_bytes(*size));
+                header.extend(u32_bytes(*offset));
+                header.extend([0; 4]);
+            }
+            let mut segment: Vec<u8> = vec![0xff, Markers::APP2];
+            segment.extend((header.len() as u16 + 6).to_be_bytes());
+            segment.extend(b"MPF\0");
+            segment.extend(header);
+            return segment
+        };
+        let primary: &[u8] = include_bytes!("images/gradient_420.jpg");
+        let secondary: &[u8] = include_bytes!("images/gray_22.jpg");
+        for big_endian in [true, false] {
+            // The segment follows the SOI, so its header starts 10 bytes in
+            let primary_size = primary.len() + mpf_segment(big_endian, &[(0, 0), (0, 0)]).len();
+            let images = [(primary_size as u32, 0), (secondary.len() as u32, primary_size as u32 - 10)];
+            let mut bytes: Vec<u8> = primary[..2].to_vec();
+            bytes.extend(mpf_segment(big_endian, &images));
+            bytes.extend(&primary[2..]);
+            bytes.extend(secondary);
+            let frame = Decoder::new(&bytes).read_segments().unwrap();
+            let ranges = frame.mpf_images();
+            assert_eq!(ranges, [0..primary_size, primary_size..bytes.len()]);
+            let gray = Decoder::new(&bytes[ranges[1].clone()]).decode().unwrap();
+            assert_eq!(gray.pixels, Decoder::new(secondary).decode().unwrap().pixels);
+            assert_eq!(Decoder::new(&bytes[ranges[0].clone()]).decode().unwrap().pixels, Decoder::new(primary).decode().unwrap().pixels);
+        }
+        // Files without an MPF segment, or with an index cut short, list nothing
+        assert!(Decoder::new(primary).read_segments().unwrap().mpf_images().is_empty());
+        let mut truncated = mpf_segment(true, &[(0, 0)]);
+        truncated.truncate(truncated.len() - 8);
+        truncated[3] -= 8;
+        let mut bytes: Vec<u8> = primary[..2].to_vec();
+        bytes.extend(truncated);
+        bytes.extend(&primary[2..]);
+        assert!(Decoder::new(&bytes).read_segments().unwrap().mpf_images().is_empty());
+    }
+}
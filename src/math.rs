@@ -0,0 +1,42 @@
+// Floating point functions used by the decode path. They come from std when
+// it is available and from libm otherwise, since core has no float math.
+
+#[cfg(feature = "std")]
+pub fn cos(x: f64) -> f64 {
+    return x.cos()
+}
+
+#[cfg(not(feature = "std"))]
+pub fn cos(x: f64) -> f64 {
+    return libm::cos(x)
+}
+
+#[cfg(feature = "std")]
+pub fn sqrt(x: f64) -> f64 {
+    return x.sqrt()
+}
+
+#[cfg(not(feature = "std"))]
+pub fn sqrt(x: f64) -> f64 {
+    return libm::sqrt(x)
+}
+
+#[cfg(feature = "std")]
+pub fn round(x: f64) -> f64 {
+    return x.round()
+}
+
+#[cfg(not(feature = "std"))]
+pub fn round(x: f64) -> f64 {
+    return libm::round(x)
+}
+
+#[cfg(feature = "std")]
+pub fn roundf(x: f32) -> f32 {
+    return x.round()
+}
+
+#[cfg(not(feature = "std"))]
+pub fn roundf(x: f32) -> f32 {
+    return libm::roundf(x)
+}
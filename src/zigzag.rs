@@ -0,0 +1,56 @@
+// The zigzag order coefficients and quantization table elements are coded in
+// (refer to figure A.6 in the spec). Both stay in zigzag order through
+// decoding and dequantization, and are only moved into the natural
+// (row-major) order the idct works in right before it.
+
+// Natural index of each coefficient in zigzag order
+pub const ZIGZAG: [usize; 64] = [
+    0,  1,  8,  16, 9,  2,  3,  10,
+    17, 24, 32, 25, 18, 11, 4,  5,
+    12, 19, 26, 33, 40, 48, 41, 34,
+    27, 20, 13, 6,  7,  14, 21, 28,
+    35, 42, 49, 56, 57, 50, 43, 36,
+    29, 22, 15, 23, 30, 37, 44, 51,
+    58, 59, 52, 45, 38, 31, 39, 46,
+    53, 60, 61, 54, 47, 55, 62, 63
+];
+
+// Zigzag index of each coefficient in natural order
+pub const INVERSE_ZIGZAG: [usize; 64] = [
+    0,  1,  5,  6,  14, 15, 27, 28,
+    2,  4,  7,  13, 16, 26, 29, 42,
+    3,  8,  12, 17, 25, 30, 41, 43,
+    9,  11, 18, 24, 31, 40, 44, 53,
+    10, 19, 23, 32, 39, 45, 52, 54,
+    20, 22, 33, 38, 46, 51, 55, 60,
+    21, 34, 37, 47, 50, 56, 59, 61,
+    35, 36, 48, 49, 57, 58, 62, 63
+];
+
+// Position in zigzag order of the coefficient at natural_idx
+pub fn to_zigzag(natural_idx: &usize) -> usize {
+    return INVERSE_ZIGZAG[*natural_idx]
+}
+
+// Natural position of the coefficient at zigzag_idx
+pub fn from_zigzag(zigzag_idx: &usize) -> usize {
+    return ZIGZAG[*zigzag_idx]
+}
+
+// Moves a block from zigzag order into natural order
+pub fn natural_order<T: Copy + Default>(block: &[T; 64]) -> [T; 64] {
+    let mut natural: [T; 64] = [T::default(); 64];
+    for (idx, value) in block.iter().enumerate() {
+        natural[from_zigzag(&idx)] = *value;
+    }
+    return natural
+}
+
+// Moves a block from natural order into zigzag order
+pub fn zigzag_order<T: Copy + Default>(block: &[T; 64]) -> [T; 64] {
+    let mut zigzag: [T; 64] = [T::default(); 64];
+    for (idx, value) in block.iter().enumerate() {
+        zigzag[to_zigzag(&idx)] = *value;
+    }
+    return zigzag
+}